@@ -4,6 +4,7 @@ use core::sync::atomic::*;
 use nvme_driver::NvmeInterface;
 use nvme_driver::DmaAllocator;
 use nvme_driver::IrqController;
+use nvme_driver::Timer;
 
 
 use lazy_static::lazy_static;
@@ -119,9 +120,21 @@ impl IrqController for IrqProvider{
 }
 
 
+// qemu virt timebase frequency is 10MHz
+const TIMEBASE_FREQ_MHZ: u64 = 10;
+
+pub struct TimerProvider;
+
+impl Timer for TimerProvider{
+    fn current_time_us() -> u64{
+        riscv::register::time::read() as u64 / TIMEBASE_FREQ_MHZ
+    }
+}
+
+
 pub fn nvme_test(){
     config_pci();
-    let nvme = NvmeInterface::<DmaProvider, IrqProvider>::new(0x40000000);
+    let nvme = NvmeInterface::<DmaProvider, IrqProvider, TimerProvider>::new(0x40000000).unwrap();
 
     
         
@@ -129,8 +142,8 @@ pub fn nvme_test(){
         let mut read_buf = [0u8; 512];
         let buff = [i as u8;512];
         let write_buf:&[u8] = &[i as u8;512];
-        nvme.write_block(i, &write_buf);
-        nvme.read_block(i, &mut read_buf);
+        nvme.write_block(i, &write_buf).unwrap();
+        nvme.read_block(i, &mut read_buf).unwrap();
         println!("{:?}", read_buf);
         assert_eq!(read_buf, buff);
     }
@@ -5,11 +5,14 @@ extern crate alloc;
 mod dma;
 mod irq;
 mod nvme;
+mod timer;
 
 pub use dma::*;
 pub use irq::*;
 pub use nvme::*;
+pub use timer::*;
 
 pub use self::dma::DmaAllocator;
 pub use self::irq::IrqController;
 pub use self::nvme::NvmeInterface;
+pub use self::timer::Timer;
@@ -1,8 +1,14 @@
+#[allow(clippy::module_inception)]
 pub mod nvme;
 pub mod nvme_queue;
 pub mod nvme_defs;
+pub mod nvme_error;
+pub mod nvme_pmr;
+pub mod nvme_kv;
 
 pub use nvme::*;
 pub use nvme_queue::*;
 pub use nvme_defs::*;
-
+pub use nvme_error::*;
+pub use nvme_pmr::*;
+pub use nvme_kv::*;
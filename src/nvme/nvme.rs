@@ -1,74 +1,358 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec;
+use alloc::slice;
 use alloc::vec::Vec;
 use core::marker::PhantomData;
+use core::mem::size_of;
 use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, AtomicBool, Ordering};
 
 use super::nvme_defs::*;
+use super::nvme_error::*;
+use super::nvme_kv::KvNamespace;
+use super::nvme_pmr::PmrRegion;
 use super::nvme_queue::*;
 use crate::dma::DmaAllocator;
 use crate::irq::IrqController;
+use crate::timer::Timer;
 use lock::Mutex;
 use lock::MutexGuard;
 
 pub const NVME_QUEUE_DEPTH: usize = 1024;
-
-pub struct NvmeInterface<D: DmaAllocator, I: IrqController> {
+pub const NVME_ADMIN_QUEUE_DEPTH: usize = 32;
+// asynchronous event requests kept outstanding
+pub const NVME_NR_AEN_COMMANDS: usize = 1;
+
+// io_queues[i] is created with priority NVME_IO_QUEUE_PRIOS[i], the first one also serves
+// callers that give no priority. without weighted round robin only the first one exists
+pub const NVME_IO_QUEUE_PRIOS: [NvmeQueuePriority; 4] = [
+    NvmeQueuePriority::Medium,
+    NvmeQueuePriority::Urgent,
+    NvmeQueuePriority::High,
+    NvmeQueuePriority::Low,
+];
+
+// format nvm may erase the whole device, allow it far more time than other commands
+pub const NVME_FORMAT_TIMEOUT_US: u64 = 600 * 1000 * 1000;
+
+// shortest interval between two reads of the sanitize status log
+pub const NVME_SANITIZE_POLL_MIN_US: u64 = 100 * 1000;
+
+// shutdown timeout when RTD3E is not reported, and the upper bound otherwise
+pub const NVME_SHUTDOWN_TIMEOUT_US: u64 = 5 * 1000 * 1000;
+pub const NVME_SHUTDOWN_TIMEOUT_MAX_US: u64 = 60 * 1000 * 1000;
+
+// boot partition reads are polled through BPINFO
+pub const NVME_BP_READ_TIMEOUT_US: u64 = 10 * 1000 * 1000;
+// bounce buffer of a boot partition read, in NVME_BP_READ_UNIT
+const NVME_BP_READ_CHUNK: usize = 32;
+
+// PMRCAP.PMRTO of 0 gives no time at all, wait at least this long for PMRSTS.NRDY
+pub const NVME_PMR_MIN_TIMEOUT_US: u64 = 500 * 1000;
+
+// a command still in flight after its timeout is aborted, then the controller is reset
+pub const NVME_ADMIN_TIMEOUT_US: u64 = 60 * 1000 * 1000;
+pub const NVME_IO_TIMEOUT_US: u64 = 30 * 1000 * 1000;
+// times a command may be resubmitted after controller fatal status
+pub const NVME_RECOVERY_RETRIES: usize = 3;
+
+pub struct NvmeInterface<D: DmaAllocator, I: IrqController, T: Timer> {
     irq_data: PhantomData<I>,
 
+    timer_data: PhantomData<T>,
+
     admin_queue: Arc<Mutex<NvmeQueue<D>>>,
 
     io_queues: Vec<Arc<Mutex<NvmeQueue<D>>>>,
 
+    // data buffers of admin and io commands, cloned before locking so the guard does
+    // not borrow the interface
+    admin_buf: Arc<Mutex<NvmeDataBuf<D>>>,
+    io_buf: Arc<Mutex<NvmeDataBuf<D>>>,
+
     bar: usize,
 
     irq: usize,
+
+    // identify data, read on first use
+    id_ctrl: Option<NvmeIdCtrl>,
+    namespaces: BTreeMap<u32, NvmeIdNs>,
+    // active namespaces as last reported to the event handler, None until the first
+    // rescan_namespaces
+    active_ns: Option<BTreeMap<u32, NvmeIdNs>>,
+
+    event_handler: Option<Box<dyn NvmeEventHandler>>,
+
+    // CC.SHN has been set, the controller must be reset before further use
+    shut_down: bool,
+
+    recovery_policy: NvmeRecoveryPolicy,
+    // held while the controller is reset after a fatal status
+    recovery: Mutex<()>,
+    // fatal status seen in interrupt context, recovered by the next submitter
+    fatal_pending: AtomicBool,
+    // platform function level reset, used when a controller reset does not work
+    pci_reset: Mutex<Option<Box<dyn NvmePciReset>>>,
+
+    // controller base address of the controller memory buffer, once enabled
+    cmb_cba: Option<u64>,
+
+    // shadow doorbell and eventidx pages, once enabled
+    dbbuf: Option<(usize, usize)>,
 }
 
-impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
     // alloc dma memory for admin queue and io queues
     // basic init for admin queue and io queues
-    pub fn new(bar: usize) -> Self {
-        let admin_queue = Arc::new(Mutex::new(NvmeQueue::new(0, 0)));
+    pub fn new(bar: usize) -> NvmeResult<Self> {
+        let admin_queue = Arc::new(Mutex::new(NvmeQueue::new(0, 0, NVME_ADMIN_QUEUE_DEPTH)));
 
-        let io_queues = vec![Arc::new(Mutex::new(NvmeQueue::new(1, 0x8)))];
+        let io_queues = vec![Arc::new(Mutex::new(NvmeQueue::new(1, 0x8, NVME_QUEUE_DEPTH)))];
 
         let mut interface = NvmeInterface {
             irq_data: PhantomData,
+            timer_data: PhantomData,
             admin_queue,
             io_queues,
+            admin_buf: Arc::new(Mutex::new(NvmeDataBuf::new())),
+            io_buf: Arc::new(Mutex::new(NvmeDataBuf::new())),
             bar,
             irq: 33,
+            id_ctrl: None,
+            namespaces: BTreeMap::new(),
+            active_ns: None,
+            event_handler: None,
+            shut_down: false,
+            recovery_policy: NvmeRecoveryPolicy::Resubmit,
+            recovery: Mutex::new(()),
+            fatal_pending: AtomicBool::new(false),
+            pci_reset: Mutex::new(None),
+            cmb_cba: None,
+            dbbuf: None,
         };
 
-        interface.init();
+        interface.init()?;
 
-        interface
+        Ok(interface)
     }
 
     // config admin queue ,io queue
-    pub fn init(&mut self) {
+    pub fn init(&mut self) -> NvmeResult<()> {
         self.nvme_configure_admin_queue();
 
-        self.nvme_alloc_io_queue();
-
+        // one queue pair per priority class under weighted round robin
+        let nr = if self.wrr_enabled() { NVME_IO_QUEUE_PRIOS.len() } else { 1 };
+        let granted = self.nvme_set_queue_count(nr as u16)? as usize;
+        for qid in self.io_queues.len() + 1..=nr.min(granted) {
+            let queue = NvmeQueue::new(qid, qid * 8, NVME_QUEUE_DEPTH);
+            self.io_queues.push(Arc::new(Mutex::new(queue)));
+        }
 
+        self.nvme_alloc_io_queue()
     }
 }
 
-impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
     // submit admin command and wait for completion
-    pub fn submit_sync_command(&mut self, cmd: NvmeCommonCommand) {
+    // Timeout and ControllerFatal tell a command that could not be completed
+    pub fn submit_sync_command(&mut self, cmd: NvmeCommonCommand) -> NvmeResult<NvmeCompletion> {
+        self.nvme_recover_pending()?;
         let mut admin_queue = self.admin_queue.lock();
-        self.send_command(&mut admin_queue, cmd);
-        self.nvme_poll_cq(&mut admin_queue);
+        let cid = self.send_command(&mut admin_queue, cmd);
+        self.nvme_wait_recover(&self.admin_queue, admin_queue, cid)
+    }
+
+    // submit admin command and wait at most timeout_us for its completion
+    pub fn submit_sync_command_timeout(
+        &mut self,
+        cmd: NvmeCommonCommand,
+        timeout_us: u64,
+    ) -> NvmeResult<NvmeCompletion> {
+        self.nvme_recover_pending()?;
+        let mut admin_queue = self.admin_queue.lock();
+        let cid = self.send_command(&mut admin_queue, cmd);
+        if let Some(inflight) = admin_queue.inflight.get_mut(&cid) {
+            inflight.deadline_us = T::current_time_us().saturating_add(timeout_us);
+        }
+        self.nvme_wait_recover(&self.admin_queue, admin_queue, cid)
+    }
+
+    // submit admin command transferring buf directly, wait for completion
+    pub fn submit_sync_command_buf(
+        &mut self,
+        cmd: NvmeCommonCommand,
+        buf_va: usize,
+        len: usize,
+    ) -> NvmeResult<NvmeCompletion> {
+        self.nvme_submit_sync_prps(cmd, buf_va, len, D::virt_to_phys)
+    }
+
+    // submit admin command on a buffer, to_phys turns a buffer address into a bus address
+    fn nvme_submit_sync_prps(
+        &mut self,
+        mut cmd: NvmeCommonCommand,
+        buf: usize,
+        len: usize,
+        to_phys: fn(usize) -> usize,
+    ) -> NvmeResult<NvmeCompletion> {
+        self.nvme_recover_pending()?;
+        let mut admin_queue = self.admin_queue.lock();
+        self.nvme_fill_prps(&mut admin_queue, &mut cmd, buf, len, to_phys)?;
+        let cid = self.send_command(&mut admin_queue, cmd);
+        self.nvme_wait_recover(&self.admin_queue, admin_queue, cid)
+    }
+
+    // fill prp1/prp2 for a dword aligned buffer, every page is translated on its own so
+    // the buffer need not be physically contiguous. buffers spanning more than two pages
+    // use a prp list page of the queue
+    // linux中对应实现 nvme_pci_setup_prps
+    pub fn nvme_setup_prps(
+        &self,
+        nvmeq: &mut MutexGuard<NvmeQueue<D>>,
+        cmd: &mut NvmeCommonCommand,
+        buf_va: usize,
+        len: usize,
+    ) -> NvmeResult<()> {
+        self.nvme_fill_prps(nvmeq, cmd, buf_va, len, D::virt_to_phys)
+    }
+
+    // buffers not in host dma memory (controller memory buffer) are addressed by their
+    // bus address, which is passed through as is
+    fn nvme_fill_prps(
+        &self,
+        nvmeq: &mut MutexGuard<NvmeQueue<D>>,
+        cmd: &mut NvmeCommonCommand,
+        buf: usize,
+        len: usize,
+        to_phys: fn(usize) -> usize,
+    ) -> NvmeResult<()> {
+        let pages = nvme_prp_pages(buf, len)?;
+        let first_page = buf - buf % PAGE_SIZE;
+
+        cmd.prp1 = to_phys(buf) as u64;
+        match pages {
+            1 => {}
+            2 => cmd.prp2 = to_phys(first_page + PAGE_SIZE) as u64,
+            _ => {
+                let (prp_list, prp_pa) = nvmeq.prp_list();
+                for i in 1..pages {
+                    prp_list[i - 1] = to_phys(first_page + i * PAGE_SIZE) as u64;
+                }
+                cmd.prp2 = prp_pa as u64;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn nvme_read_cap(&self) -> u64 {
+        let low = unsafe { read_volatile((self.bar + NVME_REG_CAP) as *const u32) };
+        let high = unsafe { read_volatile((self.bar + NVME_REG_CAP + 4) as *const u32) };
+        (high as u64) << 32 | low as u64
+    }
+
+    // largest transfer of one command, limited by MDTS and the prp list size
+    pub fn max_transfer_size(&mut self) -> NvmeResult<usize> {
+        let mdts = self.ctrl_info()?.mdts;
+        Ok(nvme_max_transfer(mdts, self.nvme_read_cap()))
+    }
+
+    // submit io command and wait for completion
+    pub fn submit_io_command(&self, cmd: NvmeCommonCommand) -> NvmeResult<NvmeCompletion> {
+        self.submit_io_command_prio(cmd, NVME_IO_QUEUE_PRIOS[0])
+    }
+
+    // submit io command on the queue of a priority class and wait for completion
+    pub fn submit_io_command_prio(
+        &self,
+        cmd: NvmeCommonCommand,
+        prio: NvmeQueuePriority,
+    ) -> NvmeResult<NvmeCompletion> {
+        self.nvme_recover_pending()?;
+        let queue = self.nvme_io_queue(prio);
+        let mut io_queue = queue.lock();
+        let cid = self.send_command(&mut io_queue, cmd);
+        self.nvme_wait_recover(queue, io_queue, cid)
+    }
+
+    // submit io command transferring buf directly on the queue of a priority class,
+    // wait for completion
+    pub fn submit_io_command_buf(
+        &self,
+        mut cmd: NvmeCommonCommand,
+        buf_va: usize,
+        len: usize,
+        prio: NvmeQueuePriority,
+    ) -> NvmeResult<NvmeCompletion> {
+        self.nvme_recover_pending()?;
+        let queue = self.nvme_io_queue(prio);
+        let mut io_queue = queue.lock();
+        self.nvme_setup_prps(&mut io_queue, &mut cmd, buf_va, len)?;
+        let cid = self.send_command(&mut io_queue, cmd);
+        self.nvme_wait_recover(queue, io_queue, cid)
+    }
+
+    // io queue of a priority class, the default one when the class has no queue of its own
+    fn nvme_io_queue(&self, prio: NvmeQueuePriority) -> &Arc<Mutex<NvmeQueue<D>>> {
+        NVME_IO_QUEUE_PRIOS
+            .iter()
+            .position(|p| *p == prio)
+            .and_then(|i| self.io_queues.get(i))
+            .unwrap_or(&self.io_queues[0])
+    }
+
+    // wait for command cid submitted on queue. when the controller reports a fatal status
+    // it is recovered according to the recovery policy, a resubmitted command is waited for
+    // again. a command passing its deadline is aborted, then the controller is reset.
+    // the queue is unlocked while aborting or recovering
+    fn nvme_wait_recover<'a>(
+        &self,
+        queue: &'a Mutex<NvmeQueue<D>>,
+        mut nvmeq: MutexGuard<'a, NvmeQueue<D>>,
+        cid: u16,
+    ) -> NvmeResult<NvmeCompletion> {
+        let mut retries = 0;
+        let mut timed_out = false;
+        loop {
+            match self.nvme_wait_cid(&mut nvmeq, cid, None) {
+                Err(NvmeError::Timeout) => {
+                    timed_out = true;
+                    drop(nvmeq);
+                    self.nvme_timeout(queue, cid)?;
+                    nvmeq = queue.lock();
+                    // failed by the reset, a completion reaped meanwhile is in completed
+                    if !nvmeq.inflight.contains_key(&cid) && !nvmeq.completed.contains_key(&cid)
+                    {
+                        return Err(NvmeError::Timeout);
+                    }
+                }
+                Err(NvmeError::ControllerFatal)
+                    if self.recovery_policy != NvmeRecoveryPolicy::Manual
+                        && retries < NVME_RECOVERY_RETRIES =>
+                {
+                    retries += 1;
+                    drop(nvmeq);
+                    self.recover()?;
+                    nvmeq = queue.lock();
+                    // failed by the policy, a completion reaped meanwhile is in completed
+                    if !nvmeq.inflight.contains_key(&cid) && !nvmeq.completed.contains_key(&cid)
+                    {
+                        return Err(NvmeError::ControllerFatal);
+                    }
+                }
+                // the abort issued for the command completed it
+                Ok(cqe) if timed_out && nvme_aborted(&cqe) => return Err(NvmeError::Timeout),
+                result => return result,
+            }
+        }
     }
 
     // config admin queue
     // 1. set admin queue(cq && sq) size
     // 2. set admin queue(cq && sq) dma address
     // 3. enable ctrl
-    pub fn nvme_configure_admin_queue(&mut self) {
+    pub fn nvme_configure_admin_queue(&self) {
         let admin_queue = self.admin_queue.lock();
 
         let bar = self.bar;
@@ -77,9 +361,9 @@ impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
         let cq_dma_pa = admin_queue.cq_pa as u32;
 
         // sq depth
-        let aqa_low_16 = 31_u16;
+        let aqa_low_16 = (admin_queue.q_depth - 1) as u16;
         // cq depth
-        let aqa_high_16 = 31_u16;
+        let aqa_high_16 = (admin_queue.q_depth - 1) as u16;
         let aqa = (aqa_high_16 as u32) << 16 | aqa_low_16 as u32;
         let aqa_address = bar + NVME_REG_AQA;
 
@@ -100,10 +384,24 @@ impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
             write_volatile(acq_address as *mut u32, cq_dma_pa);
         }
 
+        // weighted round robin when the controller supports it
+        let arb = if self.nvme_read_cap() & NVME_CAP_AMS_WRRU != 0 {
+            NVME_CC_ARB_WRRU
+        } else {
+            NVME_CC_ARB_RR
+        };
+
+        // all supported i/o command sets when the controller reports more than nvm
+        let css = if self.nvme_read_cap() & NVME_CAP_CSS_CSI != 0 {
+            NVME_CC_CSS_CSI
+        } else {
+            NVME_CC_CSS_NVM
+        };
+
         // enable ctrl
-        let mut ctrl_config = NVME_CC_ENABLE | NVME_CC_CSS_NVM;
+        let mut ctrl_config = NVME_CC_ENABLE | css;
         ctrl_config |= 0 << NVME_CC_MPS_SHIFT;
-        ctrl_config |= NVME_CC_ARB_RR | NVME_CC_SHN_NONE;
+        ctrl_config |= arb | NVME_CC_SHN_NONE;
         ctrl_config |= NVME_CC_IOSQES | NVME_CC_IOCQES;
 
         unsafe { write_volatile((bar + NVME_REG_CC) as *mut u32, ctrl_config) }
@@ -116,51 +414,143 @@ impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
         }
     }
 
-    // alloc io queue
-    // 1. set queue count through nvme_features
-    // 2. alloc io queue(cq) through admin command
-    // 3. alloc io queue(sq) through admin command
-    pub fn nvme_alloc_io_queue(&mut self) {
-        let cq_pa = self.io_queues[0].lock().cq_pa;
-        let sq_pa = self.io_queues[0].lock().sq_pa;
-
-        let q_depth = self.io_queues[0].lock().q_depth as u16;
-        // nvme_set_queue_count
-        let mut cmd = NvmeCommonCommand::new();
-        cmd.opcode = 0x09;
-        cmd.command_id = 0x2;
-        cmd.nsid = 0;
-        cmd.cdw10 = 0x7;
-        self.submit_sync_command(cmd);
-
-        //nvme create cq
-        let mut cmd = NvmeCreateCq::new();
-        cmd.opcode = 0x05;
-        cmd.command_id = 0x3;
-        cmd.nsid = 0;
-        cmd.prp1 = cq_pa as u64;
-        cmd.cqid = 1;
-        cmd.qsize = q_depth - 1;
-        cmd.cq_flags = NVME_QUEUE_PHYS_CONTIG | NVME_CQ_IRQ_ENABLED;
-        let common_cmd = unsafe { core::mem::transmute(cmd) };
-        self.submit_sync_command(common_cmd);
+    // alloc io queue, the queue count must have been set through nvme_set_queue_count
+    // 1. alloc io queue(cq) through admin command
+    // 2. alloc io queue(sq) through admin command
+    pub fn nvme_alloc_io_queue(&self) -> NvmeResult<()> {
+        for (io_queue, prio) in self.io_queues.iter().zip(NVME_IO_QUEUE_PRIOS) {
+            let (qid, cq_pa, sq_pa, q_depth) = {
+                let io_queue = io_queue.lock();
+                (io_queue.qid as u16, io_queue.cq_pa, io_queue.sq_pa, io_queue.q_depth as u16)
+            };
+
+            //nvme create cq
+            let mut cmd = NvmeCreateCq::new();
+            cmd.opcode = 0x05;
+            cmd.nsid = 0;
+            cmd.prp1 = cq_pa as u64;
+            cmd.cqid = qid;
+            cmd.qsize = q_depth - 1;
+            cmd.cq_flags = NVME_QUEUE_PHYS_CONTIG | NVME_CQ_IRQ_ENABLED;
+            let common_cmd = unsafe { core::mem::transmute(cmd) };
+            self.nvme_admin_command(common_cmd)?;
+
+            self.nvme_create_sq(qid, sq_pa, q_depth, prio)?;
+        }
+        Ok(())
+    }
 
-        // nvme create sq
+    // nvme create sq, completing on the cq of the same id
+    fn nvme_create_sq(
+        &self,
+        qid: u16,
+        sq_pa: usize,
+        q_depth: u16,
+        prio: NvmeQueuePriority,
+    ) -> NvmeResult<()> {
         let mut cmd = NvmeCreateSq::new();
         cmd.opcode = 0x01;
-        cmd.command_id = 0x4;
         cmd.nsid = 0;
         cmd.prp1 = sq_pa as u64;
-        cmd.sqid = 1;
+        cmd.sqid = qid;
         cmd.qsize = q_depth - 1;
-        cmd.sq_flags = 0x1;
-        cmd.cqid = 0x1;
+        cmd.sq_flags = NVME_QUEUE_PHYS_CONTIG | prio.sq_flags();
+        cmd.cqid = qid;
         let common_cmd = unsafe { core::mem::transmute(cmd) };
-        self.submit_sync_command(common_cmd);
+        self.nvme_admin_command(common_cmd)?;
+        Ok(())
+    }
+
+    // request nr io submission and completion queues, return how many pairs were allocated
+    fn nvme_set_queue_count(&self, nr: u16) -> NvmeResult<u16> {
+        let mut cmd = NvmeCommonCommand::new();
+        cmd.opcode = NVME_ADMIN_SET_FEATURES;
+        cmd.nsid = 0;
+        cmd.cdw10 = NVME_FEAT_NUM_QUEUES;
+        cmd.cdw11 = (nr - 1) as u32 | ((nr - 1) as u32) << 16;
+        Ok(nvme_queue_count(self.nvme_admin_command(cmd)?.result as u32))
     }
+
+    // CC.ARB selects weighted round robin with urgent priority class
+    pub fn wrr_enabled(&self) -> bool {
+        let cc = unsafe { read_volatile((self.bar + NVME_REG_CC) as *const u32) };
+        cc & NVME_CC_ARB_MASK == NVME_CC_ARB_WRRU
+    }
+
+    // program the weights of the high, medium and low priority classes, 0's based
+    pub fn set_wrr_weights(&mut self, hpw: u8, mpw: u8, lpw: u8) -> NvmeResult<()> {
+        if !self.wrr_enabled() {
+            return Err(NvmeError::NotSupported);
+        }
+        let mut arb = self.get_arbitration(NvmeFeatureSel::Current)?;
+        arb.hpw = hpw;
+        arb.mpw = mpw;
+        arb.lpw = lpw;
+        self.set_arbitration(&arb, false)
+    }
+
+    // admin command that never triggers recovery, used to bring the controller up
+    fn nvme_admin_command(&self, cmd: NvmeCommonCommand) -> NvmeResult<NvmeCompletion> {
+        let mut admin_queue = self.admin_queue.lock();
+        let cid = self.send_command(&mut admin_queue, cmd);
+        let cqe = self.nvme_wait_cid(&mut admin_queue, cid, Some(NVME_ADMIN_TIMEOUT_US))?;
+        NvmeError::from_status(cqe.status)?;
+        Ok(cqe)
+    }
+}
+
+// io queue pairs granted by the number of queues feature, sq and cq counts are 0's based
+fn nvme_queue_count(result: u32) -> u16 {
+    (result as u16).min((result >> 16) as u16).saturating_add(1)
+}
+
+// bytes of log page data one get log page command may return. without extended data the
+// dword count is the 12 bit NUMD of nvme 1.2, NUMDL and NUMDU cover any transfer otherwise
+fn nvme_log_chunk_max(max_transfer: usize, extended: bool) -> usize {
+    if extended {
+        max_transfer
+    } else {
+        max_transfer.min(0x1000 * 4)
+    }
+}
+
+// the command completed because an abort command aborted it
+fn nvme_aborted(cqe: &NvmeCompletion) -> bool {
+    matches!(
+        NvmeError::from_status(cqe.status),
+        Err(NvmeError::CommandFailed { sct: NVME_SCT_GENERIC, sc: NVME_SC_ABORT_REQ, .. })
+    )
+}
+
+// pages spanned by a dword aligned buffer, one page plus a full prp list at most
+fn nvme_prp_pages(buf_va: usize, len: usize) -> NvmeResult<usize> {
+    if buf_va % 4 != 0 || len == 0 {
+        return Err(NvmeError::InvalidArgument);
+    }
+    let pages = (buf_va % PAGE_SIZE + len + PAGE_SIZE - 1) / PAGE_SIZE;
+    if pages > NVME_PRP_LIST_ENTRIES + 1 {
+        return Err(NvmeError::InvalidArgument);
+    }
+    Ok(pages)
+}
+
+// largest transfer of one command for MDTS and CAP, the prp list size is the limit when
+// mdts is 0
+fn nvme_max_transfer(mdts: u8, cap: u64) -> usize {
+    let mut max = NVME_PRP_LIST_ENTRIES * PAGE_SIZE;
+    if mdts != 0 {
+        max = max.min(nvme_mpsmin_units(mdts, cap));
+    }
+    max
+}
+
+// bytes in 2^exp units of CAP.MPSMIN (MDTS, ZASL), saturating for exponents past usize
+fn nvme_mpsmin_units(exp: u8, cap: u64) -> usize {
+    let mpsmin = ((cap >> 48) & 0xf) as u32;
+    1usize.checked_shl(exp as u32 + 12 + mpsmin).unwrap_or(usize::MAX)
 }
 
-impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
     // 每个NVMe命令中有两个域：PRP1和PRP2，Host就是通过这两个域告诉SSD数据在内存中的位置或者数据需要写入的地址
     // 首先对prp1进行读写，如果数据还没完，就看数据量是不是在一个page内，在的话，只需要读写prp2内存地址就可以了，数据量大于1个page，就需要读出prp list
 
@@ -175,7 +565,7 @@ impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
     // SLBA = start logical block address
     // 1 SLBA = 512B
     // length = 0 = 512B
-    pub fn read_block(&self, block_id: usize, read_buf: &mut [u8]) {
+    pub fn read_block(&self, block_id: usize, read_buf: &mut [u8]) -> NvmeResult<()> {
         // buffer should be dword aligned
         // 这里dma addr 就是buffer的地址
         let ptr = read_buf.as_mut_ptr();
@@ -185,7 +575,6 @@ impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
         let mut cmd = NvmeRWCommand::new_read_command();
         cmd.nsid = 1;
         cmd.prp1 = addr as u64;
-        cmd.command_id = 101;
         cmd.length = 0;
         cmd.control = 0x8000;
         cmd.dsmgmt = 0x7;
@@ -194,16 +583,15 @@ impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
         //transfer to common command
         let common_cmd = unsafe { core::mem::transmute(cmd) };
 
-        let mut io_queue = self.io_queues[0].lock();
-        self.send_command(&mut io_queue, common_cmd);
-        self.nvme_poll_cq(&mut io_queue);
+        let cqe = self.submit_io_command(common_cmd)?;
+        NvmeError::from_status(cqe.status)
     }
 
     // prp1 = write_buf physical address
     // prp2 = 0
     // SLBA = start logical block address
     // length = 0 = 512B
-    pub fn write_block(&self, block_id: usize, write_buf: &[u8]) {
+    pub fn write_block(&self, block_id: usize, write_buf: &[u8]) -> NvmeResult<()> {
         // buffer should be dword aligned
         let ptr = write_buf.as_ptr();
         let addr = D::virt_to_phys(ptr as usize);
@@ -213,7 +601,6 @@ impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
         cmd.nsid = 1;
         cmd.prp1 = addr as u64;
         cmd.length = 0;
-        cmd.command_id = 100;
         cmd.slba = block_id as u64;
         cmd.control = 0;
         cmd.dsmgmt = 0;
@@ -221,55 +608,12 @@ impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
         // transmute to common command
         let common_cmd = unsafe { core::mem::transmute(cmd) };
 
-        let mut io_queue = self.io_queues[0].lock();
-        self.send_command(&mut io_queue, common_cmd);
-        self.nvme_poll_cq(&mut io_queue);
-    }
-
-    fn send_read_command(&self, block_id: usize, read_buf: &mut [u8], cid: usize) -> usize {
-        // buffer should be dword aligned
-        // 这里dma addr 就是buffer的地址
-        let ptr = read_buf.as_mut_ptr();
-        let addr = D::virt_to_phys(ptr as usize);
-
-        // build nvme read command
-        let mut cmd = NvmeRWCommand::new_read_command();
-        cmd.nsid = 1;
-        cmd.prp1 = addr as u64;
-        cmd.command_id = cid as u16;
-        cmd.length = 0;
-        cmd.slba = block_id as u64;
-
-        //transfer to common command
-        let common_cmd = unsafe { core::mem::transmute(cmd) };
-
-        let mut io_queue = self.io_queues[0].lock();
-        self.send_command(&mut io_queue, common_cmd);
-
-        cid as usize
-    }
-    fn send_write_command(&self, block_id: usize, write_buf: &[u8], cid: usize) -> usize {
-        // buffer should be dword aligned
-        let ptr = write_buf.as_ptr();
-        let addr = D::virt_to_phys(ptr as usize);
-        // build nvme write command
-        let mut cmd = NvmeRWCommand::new_write_command();
-        cmd.nsid = 1;
-        cmd.prp1 = addr as u64;
-        cmd.length = 0;
-        cmd.command_id = cid as u16;
-        cmd.slba = block_id as u64;
-        // transmute to common command
-        let common_cmd = unsafe { core::mem::transmute(cmd) };
-
-        let mut io_queue = self.io_queues[0].lock();
-        self.send_command(&mut io_queue, common_cmd);
-
-        cid as usize
+        let cqe = self.submit_io_command(common_cmd)?;
+        NvmeError::from_status(cqe.status)
     }
 }
 
-impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
     pub fn nvme_poll_irqdisable(&self) {
         I::disable_irq(self.irq);
 
@@ -277,7 +621,32 @@ impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
     }
 
     // write command to submission queue and write sq doorbell to notify nvme device
-    pub fn send_command(&self, nvmeq: &mut MutexGuard<NvmeQueue<D>>, cmd: NvmeCommonCommand) {
+    // the command id is allocated by the queue and returned
+    pub fn send_command(&self, nvmeq: &mut MutexGuard<NvmeQueue<D>>, mut cmd: NvmeCommonCommand) -> u16 {
+        cmd.command_id = nvmeq.alloc_cid();
+        self.nvme_queue_command(nvmeq, cmd);
+        cmd.command_id
+    }
+
+    // write a command keeping its command id, its deadline starts now
+    fn nvme_queue_command(&self, nvmeq: &mut MutexGuard<NvmeQueue<D>>, cmd: NvmeCommonCommand) {
+        let deadline_us = if nvmeq.qid == 0 && cmd.opcode == NVME_ADMIN_ASYNC_EVENT {
+            // completes only when an event occurs
+            u64::MAX
+        } else if nvmeq.qid == 0 {
+            T::current_time_us().saturating_add(NVME_ADMIN_TIMEOUT_US)
+        } else {
+            T::current_time_us().saturating_add(NVME_IO_TIMEOUT_US)
+        };
+        let inflight = NvmeInflight {
+            cmd,
+            deadline_us,
+            aborted: false,
+            waited: false,
+        };
+        nvmeq.inflight.insert(cmd.command_id, inflight);
+        nvmeq.claim_prp_list(cmd.command_id);
+
         let sq_tail = nvmeq.sq_tail;
         nvmeq.sq[sq_tail].write(cmd);
 
@@ -290,29 +659,103 @@ impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
         self.nvme_write_sq_db(nvmeq, true);
     }
 
-    // check completion queue and update cq head cq doorbell until there is no pending command
-    pub fn nvme_poll_cq(&self, nvmeq: &mut MutexGuard<NvmeQueue<D>>) {
+    // wait for the next completion, update cq head and cq doorbell
+    pub fn nvme_poll_cq(&self, nvmeq: &mut MutexGuard<NvmeQueue<D>>) -> NvmeCompletion {
 
         while !self.nvme_cqe_pending(nvmeq) {
         }
+        self.nvme_reap_cqe(nvmeq)
+    }
+
+    // wait for the completion of command cid, other completions are retired on the way
+    // fails with Timeout past the command deadline or ControllerFatal
+    pub fn nvme_wait_command(
+        &self,
+        nvmeq: &mut MutexGuard<NvmeQueue<D>>,
+        cid: u16,
+    ) -> NvmeResult<NvmeCompletion> {
+        self.nvme_wait_cid(nvmeq, cid, None)
+    }
+
+    // same as nvme_wait_command, but give up after timeout_us
+    pub fn nvme_wait_command_timeout(
+        &self,
+        nvmeq: &mut MutexGuard<NvmeQueue<D>>,
+        cid: u16,
+        timeout_us: u64,
+    ) -> NvmeResult<NvmeCompletion> {
+        let result = self.nvme_wait_cid(nvmeq, cid, Some(timeout_us));
+        if result.is_err() {
+            // nobody collects a later completion
+            if let Some(inflight) = nvmeq.inflight.get_mut(&cid) {
+                inflight.waited = false;
+            }
+        }
+        result
+    }
+
+    // the command stays in flight when the controller is found in fatal status or the
+    // command passes its deadline, recovery decides whether it is resubmitted
+    fn nvme_wait_cid(
+        &self,
+        nvmeq: &mut MutexGuard<NvmeQueue<D>>,
+        cid: u16,
+        timeout_us: Option<u64>,
+    ) -> NvmeResult<NvmeCompletion> {
+        let mut deadline_us = u64::MAX;
+        if let Some(inflight) = nvmeq.inflight.get_mut(&cid) {
+            inflight.waited = true;
+            deadline_us = inflight.deadline_us;
+        }
+        if let Some(timeout_us) = timeout_us {
+            deadline_us = deadline_us.min(T::current_time_us().saturating_add(timeout_us));
+        }
+        loop {
+            // possibly reaped by whoever held the queue before
+            if let Some(cqe) = nvmeq.completed.remove(&cid) {
+                return Ok(cqe);
+            }
+            while !self.nvme_cqe_pending(nvmeq) {
+                if self.controller_fatal() {
+                    return Err(NvmeError::ControllerFatal);
+                }
+                if T::current_time_us() > deadline_us {
+                    return Err(NvmeError::Timeout);
+                }
+            }
+            self.nvme_reap_cqe(nvmeq);
+        }
+    }
+
+    // consume the completion at cq head, completions of asynchronous event requests
+    // are kept for process_async_events
+    pub fn nvme_reap_cqe(&self, nvmeq: &mut MutexGuard<NvmeQueue<D>>) -> NvmeCompletion {
+        let cqe = nvmeq.cq[nvmeq.cq_head].read();
+        if let Some(cmd) = nvmeq.complete_command(&cqe) {
+            if nvmeq.qid == 0 && cmd.opcode == NVME_ADMIN_ASYNC_EVENT {
+                nvmeq.async_events.push_back(cqe);
+            }
+        }
         self.nvme_update_cq_head(nvmeq);
         self.nvme_ring_cq_doorbell(nvmeq);
+        cqe
     }
 
     // check if there is completed command in completion queue
     pub fn nvme_cqe_pending(&self, nvmeq: &mut MutexGuard<NvmeQueue<D>>) -> bool {
         let cq_head = nvmeq.cq_head;
         let cqe = nvmeq.cq[cq_head].read();
-        if (cqe.status & 1) == (nvmeq.cq_phase as u16) {
-            return true;
-        } else {
-            return false;
-        }
+        (cqe.status & 1) == (nvmeq.cq_phase as u16)
     }
 
     // notify nvme device we've completed the command
     pub fn nvme_ring_cq_doorbell(&self, nvmeq: &mut MutexGuard<NvmeQueue<D>>) {
         let cq_head = nvmeq.cq_head;
+        if nvmeq.dbbuf_db != 0
+            && !nvme_dbbuf_update(nvmeq.dbbuf_db + 0x4, nvmeq.dbbuf_ei + 0x4, cq_head as u16)
+        {
+            return;
+        }
         let q_db = self.bar + NVME_REG_DBS + nvmeq.db_offset;
         unsafe { write_volatile((q_db + 0x4) as *mut u32, cq_head as u32) }
     }
@@ -329,9 +772,14 @@ impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
             }
         }
 
+        nvmeq.last_sq_tail = nvmeq.sq_tail;
+        if nvmeq.dbbuf_db != 0
+            && !nvme_dbbuf_update(nvmeq.dbbuf_db, nvmeq.dbbuf_ei, nvmeq.sq_tail as u16)
+        {
+            return;
+        }
         let db = self.bar + NVME_REG_DBS + nvmeq.db_offset;
         unsafe { write_volatile(db as *mut u32, nvmeq.sq_tail as u32) }
-        nvmeq.last_sq_tail = nvmeq.sq_tail;
     }
 
     // update completion queue head
@@ -346,103 +794,2722 @@ impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
     }
 
     pub fn handle_irq(&self) {
-        let mut io_queue = self.io_queues[0].lock();
+        for io_queue in self.io_queues.iter() {
+            let mut io_queue = io_queue.lock();
+
+            if self.nvme_cqe_pending(&mut io_queue) {
+                self.nvme_reap_cqe(&mut io_queue);
+            }
+        }
 
-        if self.nvme_cqe_pending(&mut io_queue) {
-            self.nvme_update_cq_head(&mut io_queue);
-            self.nvme_ring_cq_doorbell(&mut io_queue);
+        // recovery waits for the controller and takes every queue lock, leave it to
+        // thread context
+        if self.controller_fatal() {
+            self.fatal_pending.store(true, Ordering::Release);
         }
     }
 }
 
-impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
-    pub fn set_features(&mut self, fid: u32, dword11: u32) {
-        let cmd = NvmeFeatures::new(fid, dword11);
+// write the shadow doorbell, the mmio doorbell is only needed when the new value
+// passed the eventidx the controller set (linux nvme_dbbuf_need_event)
+fn nvme_dbbuf_update(db: usize, ei: usize, value: u16) -> bool {
+    let old = unsafe { read_volatile(db as *const u32) } as u16;
+    unsafe { write_volatile(db as *mut u32, value as u32) }
+    // the shadow write must be visible before eventidx is read
+    fence(Ordering::SeqCst);
+    let event_idx = unsafe { read_volatile(ei as *const u32) } as u16;
+    value.wrapping_sub(event_idx).wrapping_sub(1) < value.wrapping_sub(old)
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // raw set features, return dword0 of the completion
+    pub fn set_features(&mut self, fid: u32, dword11: u32) -> NvmeResult<u32> {
+        self.nvme_set_features(fid, 0, dword11, false, None)
+    }
+
+    // raw get features, return dword0 of the completion
+    pub fn get_features(&mut self, fid: u32, sel: NvmeFeatureSel) -> NvmeResult<u32> {
+        Ok(self.nvme_get_features(fid, 0, sel, 0, 0)?.0)
+    }
+
+    // NVME_FEAT_CAP_* bits of the feature
+    pub fn feature_capabilities(&mut self, fid: u32) -> NvmeResult<u32> {
+        self.get_features(fid, NvmeFeatureSel::Supported)
+    }
+
+    // data is copied to the admin data buffer
+    fn nvme_set_features(
+        &mut self,
+        fid: u32,
+        nsid: u32,
+        dword11: u32,
+        save: bool,
+        data: Option<&[u8]>,
+    ) -> NvmeResult<u32> {
+        if save && self.ctrl_info()?.oncs & NVME_CTRL_ONCS_SAVE_SELECT == 0 {
+            return Err(NvmeError::NotSupported);
+        }
+
+        let mut cmd = NvmeFeatures::new(fid, dword11);
+        cmd.nsid = nsid;
+        if save {
+            cmd.fid |= NVME_FEAT_SAVE;
+        }
+        let admin_buf = self.admin_buf.clone();
+        let mut buf = admin_buf.lock();
+        if let Some(data) = data {
+            buf.as_mut_slice()[..data.len()].copy_from_slice(data);
+            cmd.prp1 = buf.pa as u64;
+        }
+
+        let common_cmd = unsafe { core::mem::transmute(cmd) };
+        let cqe = self.submit_sync_command(common_cmd)?;
+        NvmeError::from_status(cqe.status)?;
+        Ok(cqe.result as u32)
+    }
+
+    // data_len bytes of feature data are copied out of the admin data buffer
+    fn nvme_get_features(
+        &mut self,
+        fid: u32,
+        nsid: u32,
+        sel: NvmeFeatureSel,
+        dword11: u32,
+        data_len: usize,
+    ) -> NvmeResult<(u32, Vec<u8>)> {
+        let select_supported = self.ctrl_info()?.oncs & NVME_CTRL_ONCS_SAVE_SELECT != 0;
+        if sel != NvmeFeatureSel::Current && !select_supported {
+            return Err(NvmeError::NotSupported);
+        }
+
+        let admin_buf = self.admin_buf.clone();
+        let buf = admin_buf.lock();
+
+        let mut cmd = NvmeFeatures::new(fid | (sel as u32) << 8, dword11);
+        cmd.opcode = NVME_ADMIN_GET_FEATURES;
+        cmd.nsid = nsid;
+        if data_len != 0 {
+            cmd.prp1 = buf.pa as u64;
+        }
+
         let common_cmd = unsafe { core::mem::transmute(cmd) };
-        self.submit_sync_command(common_cmd);
+        let cqe = self.submit_sync_command(common_cmd)?;
+        NvmeError::from_status(cqe.status)?;
+        Ok((cqe.result as u32, buf.as_slice()[..data_len].to_vec()))
     }
 }
 
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    pub fn get_arbitration(&mut self, sel: NvmeFeatureSel) -> NvmeResult<NvmeArbitration> {
+        let result = self.get_features(NVME_FEAT_ARBITRATION, sel)?;
+        Ok(NvmeArbitration::from_dword(result))
+    }
 
+    pub fn set_arbitration(&mut self, arb: &NvmeArbitration, save: bool) -> NvmeResult<()> {
+        self.nvme_set_features(NVME_FEAT_ARBITRATION, 0, arb.to_dword(), save, None)?;
+        Ok(())
+    }
 
-// impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
-//     pub fn alloc_ns(&mut self,
-//         nsid: u32, 
-//         max_sectors: u32
-//     ){
+    pub fn get_power_mgmt(&mut self, sel: NvmeFeatureSel) -> NvmeResult<NvmePowerMgmt> {
+        let result = self.get_features(NVME_FEAT_POWER_MGMT, sel)?;
+        Ok(NvmePowerMgmt::from_dword(result))
+    }
 
+    pub fn set_power_mgmt(&mut self, pm: &NvmePowerMgmt, save: bool) -> NvmeResult<()> {
+        self.nvme_set_features(NVME_FEAT_POWER_MGMT, 0, pm.to_dword(), save, None)?;
+        Ok(())
+    }
 
+    pub fn get_lba_range_types(
+        &mut self,
+        nsid: u32,
+        sel: NvmeFeatureSel,
+    ) -> NvmeResult<Vec<NvmeLbaRangeType>> {
+        let (result, data) = self.nvme_get_features(NVME_FEAT_LBA_RANGE, nsid, sel, 0, PAGE_SIZE)?;
+        // number of entries, 0's based
+        let num = (result & 0x3f) as usize + 1;
+        Ok(data
+            .chunks_exact(NvmeLbaRangeType::SIZE)
+            .take(num)
+            .map(NvmeLbaRangeType::parse)
+            .collect())
+    }
 
+    pub fn set_lba_range_types(
+        &mut self,
+        nsid: u32,
+        ranges: &[NvmeLbaRangeType],
+        save: bool,
+    ) -> NvmeResult<()> {
+        if ranges.is_empty() || ranges.len() > PAGE_SIZE / NvmeLbaRangeType::SIZE {
+            return Err(NvmeError::InvalidArgument);
+        }
 
+        let mut data = [0u8; PAGE_SIZE];
+        for (i, range) in ranges.iter().enumerate() {
+            range.write_to(&mut data[i * NvmeLbaRangeType::SIZE..]);
+        }
+        let num = (ranges.len() - 1) as u32;
+        let len = ranges.len() * NvmeLbaRangeType::SIZE;
+        self.nvme_set_features(NVME_FEAT_LBA_RANGE, nsid, num, save, Some(&data[..len]))?;
+        Ok(())
+    }
 
-//     }
-// }
+    // threshold of temperature sensor (0 = composite), kelvin
+    // under = true selects the under temperature threshold
+    pub fn get_temp_threshold(
+        &mut self,
+        sensor: u8,
+        under: bool,
+        sel: NvmeFeatureSel,
+    ) -> NvmeResult<u16> {
+        let dword11 = ((sensor & 0xf) as u32) << 16 | (under as u32) << 20;
+        let (result, _) = self.nvme_get_features(NVME_FEAT_TEMP_THRESH, 0, sel, dword11, 0)?;
+        Ok(result as u16)
+    }
 
-// // async read/write
-// use core::{
-//     future::Future,
-//     pin::Pin,
-//     task::{Context, Poll},
-// };
-// use core::sync::atomic::{AtomicBool, Ordering, AtomicUsize};
+    pub fn set_temp_threshold(
+        &mut self,
+        sensor: u8,
+        under: bool,
+        kelvin: u16,
+        save: bool,
+    ) -> NvmeResult<()> {
+        let dword11 = kelvin as u32 | ((sensor & 0xf) as u32) << 16 | (under as u32) << 20;
+        self.nvme_set_features(NVME_FEAT_TEMP_THRESH, 0, dword11, save, None)?;
+        Ok(())
+    }
 
-// impl<D: DmaAllocator, I: IrqController> NvmeInterface<D, I> {
-//     fn async_read_block(&self, block_id: usize, read_buf: &mut [u8]) -> NvmeFuture{
-//         let cid = NVME_COMMAND_ID.lock().load(Ordering::SeqCst);
-//         NVME_COMMAND_ID.lock().store(cid + 1, Ordering::Relaxed);
+    pub fn get_error_recovery(
+        &mut self,
+        nsid: u32,
+        sel: NvmeFeatureSel,
+    ) -> NvmeResult<NvmeErrorRecovery> {
+        let (result, _) = self.nvme_get_features(NVME_FEAT_ERR_RECOVERY, nsid, sel, 0, 0)?;
+        Ok(NvmeErrorRecovery::from_dword(result))
+    }
 
-//         self.send_read_command(block_id, read_buf, cid);
-//         let f =  NvmeFuture::new(cid);
-//         f
-//     }
+    pub fn set_error_recovery(
+        &mut self,
+        nsid: u32,
+        er: &NvmeErrorRecovery,
+        save: bool,
+    ) -> NvmeResult<()> {
+        self.nvme_set_features(NVME_FEAT_ERR_RECOVERY, nsid, er.to_dword(), save, None)?;
+        Ok(())
+    }
 
-//     async fn async_write_block(&self, block_id: usize, write_buf: &[u8]) -> NvmeFuture{
-//         let cid = NVME_COMMAND_ID.lock().load(Ordering::SeqCst);
-//         NVME_COMMAND_ID.lock().store(cid + 1, Ordering::Relaxed);
+    pub fn get_volatile_wc(&mut self, sel: NvmeFeatureSel) -> NvmeResult<bool> {
+        Ok(self.get_features(NVME_FEAT_VOLATILE_WC, sel)? & 0x1 != 0)
+    }
 
-//         self.send_write_command(block_id, write_buf, cid);
-//         let f =  NvmeFuture::new(cid);
-//         f
-//     }
-// }
+    pub fn set_volatile_wc(&mut self, enable: bool, save: bool) -> NvmeResult<()> {
+        self.nvme_set_features(NVME_FEAT_VOLATILE_WC, 0, enable as u32, save, None)?;
+        Ok(())
+    }
 
-// lazy_static::lazy_static! {
-//     pub static ref NVME_MAP: Mutex<BTreeMap<usize, (Waker, Arc<AtomicBool>)>> = Mutex::new(BTreeMap::new());
-// }
+    // number of io submission and completion queues allocated by the controller
+    pub fn get_num_queues(&mut self, sel: NvmeFeatureSel) -> NvmeResult<(u16, u16)> {
+        let result = self.get_features(NVME_FEAT_NUM_QUEUES, sel)?;
+        Ok((result as u16 + 1, (result >> 16) as u16 + 1))
+    }
 
+    // request nsq submission and ncq completion queues, return the allocated numbers
+    pub fn set_num_queues(&mut self, nsq: u16, ncq: u16) -> NvmeResult<(u16, u16)> {
+        if nsq == 0 || ncq == 0 {
+            return Err(NvmeError::InvalidArgument);
+        }
+        let dword11 = (nsq - 1) as u32 | ((ncq - 1) as u32) << 16;
+        let result = self.nvme_set_features(NVME_FEAT_NUM_QUEUES, 0, dword11, false, None)?;
+        Ok((result as u16 + 1, (result >> 16) as u16 + 1))
+    }
 
-// lazy_static::lazy_static! {
-//     pub static ref NVME_COMMAND_ID: Mutex<AtomicUsize> = Mutex::new(AtomicUsize::new(0));
-// }
+    pub fn get_irq_coalesce(&mut self, sel: NvmeFeatureSel) -> NvmeResult<NvmeIrqCoalesce> {
+        let result = self.get_features(NVME_FEAT_IRQ_COALESCE, sel)?;
+        Ok(NvmeIrqCoalesce::from_dword(result))
+    }
 
+    pub fn set_irq_coalesce(&mut self, coalesce: &NvmeIrqCoalesce, save: bool) -> NvmeResult<()> {
+        self.nvme_set_features(NVME_FEAT_IRQ_COALESCE, 0, coalesce.to_dword(), save, None)?;
+        Ok(())
+    }
 
-// pub struct NvmeFuture{
-//     command_id: usize,
-//     irq_occurred: Arc<AtomicBool>,
-    
-// }
+    // coalescing disable of interrupt vector iv
+    pub fn get_irq_config(&mut self, iv: u16, sel: NvmeFeatureSel) -> NvmeResult<bool> {
+        let (result, _) = self.nvme_get_features(NVME_FEAT_IRQ_CONFIG, 0, sel, iv as u32, 0)?;
+        Ok(result & (1 << 16) != 0)
+    }
 
-// impl NvmeFuture{
-//     fn new(id: usize)-> Self{
+    pub fn set_irq_config(
+        &mut self,
+        iv: u16,
+        coalescing_disable: bool,
+        save: bool,
+    ) -> NvmeResult<()> {
+        let dword11 = iv as u32 | (coalescing_disable as u32) << 16;
+        self.nvme_set_features(NVME_FEAT_IRQ_CONFIG, 0, dword11, save, None)?;
+        Ok(())
+    }
 
-//         Self {
-//             command_id: id,
-//             irq_occurred: Arc::new(AtomicBool::new(false))
-//         }
-//     }
-// }
+    // disable normal: only AWUPF governs write atomicity when set
+    pub fn get_write_atomicity(&mut self, sel: NvmeFeatureSel) -> NvmeResult<bool> {
+        Ok(self.get_features(NVME_FEAT_WRITE_ATOMIC, sel)? & 0x1 != 0)
+    }
 
-// impl Future for NvmeFuture{
+    pub fn set_write_atomicity(&mut self, disable_normal: bool, save: bool) -> NvmeResult<()> {
+        self.nvme_set_features(NVME_FEAT_WRITE_ATOMIC, 0, disable_normal as u32, save, None)?;
+        Ok(())
+    }
 
-//     type Output = ();
+    // NVME_AEN_CFG_* bits
+    pub fn get_async_event_config(&mut self, sel: NvmeFeatureSel) -> NvmeResult<u32> {
+        self.get_features(NVME_FEAT_ASYNC_EVENT, sel)
+    }
 
-//     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-//         let waker = cx.waker().clone();
-//         if !self.irq_occurred.load(Ordering::SeqCst) {
-//             NVME_MAP.lock().insert(self.command_id, (waker, self.irq_occurred.clone()),);
-//         }else{
-//             return Poll::Ready(());
-//         }
-//         Poll::Pending
-//     }
-// }
+    pub fn set_async_event_config(&mut self, config: u32, save: bool) -> NvmeResult<()> {
+        self.nvme_set_features(NVME_FEAT_ASYNC_EVENT, 0, config, save, None)?;
+        Ok(())
+    }
+
+    pub fn get_timestamp(&mut self, sel: NvmeFeatureSel) -> NvmeResult<NvmeTimestamp> {
+        let (_, data) = self.nvme_get_features(NVME_FEAT_TIMESTAMP, 0, sel, 0, 8)?;
+        Ok(NvmeTimestamp::parse(&data))
+    }
+
+    // milliseconds since midnight 01-Jan-1970 UTC
+    pub fn set_timestamp(&mut self, timestamp_ms: u64, save: bool) -> NvmeResult<()> {
+        let data = (timestamp_ms & 0xffff_ffff_ffff).to_le_bytes();
+        self.nvme_set_features(NVME_FEAT_TIMESTAMP, 0, 0, save, Some(&data))?;
+        Ok(())
+    }
+
+    pub fn get_host_behavior(&mut self, sel: NvmeFeatureSel) -> NvmeResult<NvmeHostBehavior> {
+        let (_, data) = self.nvme_get_features(NVME_FEAT_HOST_BEHAVIOR, 0, sel, 0, 512)?;
+        Ok(NvmeHostBehavior::parse(&data))
+    }
+
+    pub fn set_host_behavior(&mut self, behavior: &NvmeHostBehavior, save: bool) -> NvmeResult<()> {
+        let mut data = [0u8; 512];
+        behavior.write_to(&mut data);
+        self.nvme_set_features(NVME_FEAT_HOST_BEHAVIOR, 0, 0, save, Some(&data))?;
+        Ok(())
+    }
+
+    // pre-boot software load count
+    pub fn get_sw_progress(&mut self, sel: NvmeFeatureSel) -> NvmeResult<u8> {
+        Ok(self.get_features(NVME_FEAT_SW_PROGRESS, sel)? as u8)
+    }
+
+    pub fn set_sw_progress(&mut self, pbslc: u8, save: bool) -> NvmeResult<()> {
+        self.nvme_set_features(NVME_FEAT_SW_PROGRESS, 0, pbslc as u32, save, None)?;
+        Ok(())
+    }
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // identify data, copied out of the admin data buffer
+    pub fn identify(&mut self, nsid: u32, cns: u8) -> NvmeResult<Vec<u8>> {
+        self.identify_csi(nsid, cns, NVME_CSI_NVM)
+    }
+
+    // identify data specific to the i/o command set csi
+    pub fn identify_csi(&mut self, nsid: u32, cns: u8, csi: u8) -> NvmeResult<Vec<u8>> {
+        self.nvme_identify(nsid, cns, csi, 0)
+    }
+
+    fn nvme_identify(
+        &mut self,
+        nsid: u32,
+        cns: u8,
+        csi: u8,
+        ctrlid: u16,
+    ) -> NvmeResult<Vec<u8>> {
+        let admin_buf = self.admin_buf.clone();
+        let buf = admin_buf.lock();
+
+        let mut cmd = NvmeIdentify::new();
+        cmd.nsid = nsid;
+        cmd.cns = cns;
+        cmd.csi = csi;
+        cmd.ctrlid = ctrlid;
+        cmd.prp1 = buf.pa as u64;
+        let common_cmd = unsafe { core::mem::transmute(cmd) };
+        let cqe = self.submit_sync_command(common_cmd)?;
+        NvmeError::from_status(cqe.status)?;
+        Ok(buf.as_slice()[..PAGE_SIZE].to_vec())
+    }
+
+    // identify controller, cached after the first call
+    pub fn ctrl_info(&mut self) -> NvmeResult<NvmeIdCtrl> {
+        if let Some(id_ctrl) = self.id_ctrl {
+            return Ok(id_ctrl);
+        }
+        let data = self.identify(0, NVME_ID_CNS_CTRL)?;
+        let id_ctrl = NvmeIdCtrl::parse(&data);
+        self.id_ctrl = Some(id_ctrl);
+        Ok(id_ctrl)
+    }
+
+    // identify namespace, cached after the first call
+    pub fn ns_info(&mut self, nsid: u32) -> NvmeResult<NvmeIdNs> {
+        if let Some(id_ns) = self.namespaces.get(&nsid) {
+            return Ok(*id_ns);
+        }
+        let data = self.identify(nsid, NVME_ID_CNS_NS)?;
+        let id_ns = NvmeIdNs::parse(&data);
+        self.namespaces.insert(nsid, id_ns);
+        Ok(id_ns)
+    }
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // copy source ranges to dest_slba inside the namespace
+    // use the Copy command when the controller supports it and the request is within
+    // the namespace limits (MSSRL, MCL, MSRC), otherwise fall back to read + write
+    pub fn copy(&mut self, nsid: u32, ranges: &[NvmeSourceRange], dest_slba: u64) -> NvmeResult<()> {
+        if ranges.is_empty() {
+            return Ok(());
+        }
+
+        if self.copy_supported(nsid, ranges)? {
+            self.nvme_copy(nsid, ranges, dest_slba)
+        } else {
+            self.host_copy(nsid, ranges, dest_slba)
+        }
+    }
+
+    fn copy_supported(&mut self, nsid: u32, ranges: &[NvmeSourceRange]) -> NvmeResult<bool> {
+        let id_ctrl = self.ctrl_info()?;
+        if id_ctrl.oncs & NVME_CTRL_ONCS_COPY == 0 || id_ctrl.ocfs & NVME_CTRL_OCFS_FORMAT0 == 0 {
+            return Ok(false);
+        }
+
+        Ok(self.ns_info(nsid)?.copy_within_limits(ranges))
+    }
+
+    // range list is placed in the io data buffer
+    fn nvme_copy(&self, nsid: u32, ranges: &[NvmeSourceRange], dest_slba: u64) -> NvmeResult<()> {
+        let mut buf = self.io_buf.lock();
+        let list = unsafe {
+            slice::from_raw_parts_mut(
+                buf.as_mut_slice().as_mut_ptr() as *mut NvmeSourceRange,
+                ranges.len(),
+            )
+        };
+        list.copy_from_slice(ranges);
+
+        let mut cmd = NvmeCopyCommand::new();
+        cmd.nsid = nsid;
+        cmd.prp1 = buf.pa as u64;
+        if ranges.len() * size_of::<NvmeSourceRange>() > PAGE_SIZE {
+            cmd.prp2 = (buf.pa + PAGE_SIZE) as u64;
+        }
+        cmd.sdlba = dest_slba;
+        cmd.nr = (ranges.len() - 1) as u8;
+
+        let common_cmd = unsafe { core::mem::transmute(cmd) };
+        let cqe = self.submit_io_command(common_cmd)?;
+        NvmeError::from_status(cqe.status)
+    }
+
+    // bounce the blocks through the io data buffer
+    fn host_copy(&mut self, nsid: u32, ranges: &[NvmeSourceRange], dest_slba: u64) -> NvmeResult<()> {
+        let lba_size = self.ns_info(nsid)?.lba_size();
+        // prp1 + prp2 without a prp list
+        let max_blocks = (PAGE_SIZE * 2 / lba_size) as u64;
+        if max_blocks == 0 {
+            return Err(NvmeError::NotSupported);
+        }
+        // held until the last write is done
+        let buf = self.io_buf.lock();
+        let data_pa = buf.pa;
+
+        let mut dest = dest_slba;
+        for range in ranges {
+            let mut slba = range.slba;
+            let mut remain = range.nlb as u64 + 1;
+            while remain > 0 {
+                let nlb = remain.min(max_blocks);
+                self.nvme_rw_sync(NVME_CMD_READ, nsid, slba, nlb, data_pa, lba_size)?;
+                self.nvme_rw_sync(NVME_CMD_WRITE, nsid, dest, nlb, data_pa, lba_size)?;
+                slba += nlb;
+                dest += nlb;
+                remain -= nlb;
+            }
+        }
+        Ok(())
+    }
+
+    // read or write nlb blocks from/to a physically contiguous buffer of at most two pages
+    fn nvme_rw_sync(
+        &self,
+        opcode: u8,
+        nsid: u32,
+        slba: u64,
+        nlb: u64,
+        data_pa: usize,
+        lba_size: usize,
+    ) -> NvmeResult<()> {
+        let mut cmd = NvmeRWCommand::new_read_command();
+        cmd.opcode = opcode;
+        cmd.nsid = nsid;
+        cmd.prp1 = data_pa as u64;
+        if nlb as usize * lba_size > PAGE_SIZE {
+            cmd.prp2 = (data_pa + PAGE_SIZE) as u64;
+        }
+        cmd.slba = slba;
+        cmd.length = (nlb - 1) as u16;
+
+        let common_cmd = unsafe { core::mem::transmute(cmd) };
+        let cqe = self.submit_io_command(common_cmd)?;
+        NvmeError::from_status(cqe.status)
+    }
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // host identifier used by the controller to tell registrants apart
+    // 8 bytes, or 16 bytes for the extended host identifier
+    pub fn set_host_id(&mut self, host_id: &[u8]) -> NvmeResult<()> {
+        let exhid = match host_id.len() {
+            8 => 0,
+            16 => NVME_HOST_ID_EXHID,
+            _ => return Err(NvmeError::InvalidArgument),
+        };
+
+        self.nvme_set_features(NVME_FEAT_HOST_ID, 0, exhid, false, Some(host_id))?;
+        Ok(())
+    }
+
+    // register, unregister or replace the reservation key of this host
+    pub fn reservation_register(
+        &mut self,
+        nsid: u32,
+        action: NvmeRegisterAction,
+        iekey: bool,
+        cptpl: NvmePtplChange,
+        crkey: u64,
+        nrkey: u64,
+    ) -> NvmeResult<()> {
+        let mut cdw10 = action as u32 | (cptpl as u32) << 30;
+        if iekey {
+            cdw10 |= NVME_RESV_IEKEY;
+        }
+
+        let mut key = [0u8; 16];
+        key[..8].copy_from_slice(&crkey.to_le_bytes());
+        key[8..].copy_from_slice(&nrkey.to_le_bytes());
+        self.nvme_resv_command(NVME_CMD_RESV_REGISTER, nsid, cdw10, &key)
+    }
+
+    // acquire or preempt a reservation
+    pub fn reservation_acquire(
+        &mut self,
+        nsid: u32,
+        action: NvmeAcquireAction,
+        rtype: NvmeReservationType,
+        iekey: bool,
+        crkey: u64,
+        prkey: u64,
+    ) -> NvmeResult<()> {
+        let mut cdw10 = action as u32 | (rtype as u32) << 8;
+        if iekey {
+            cdw10 |= NVME_RESV_IEKEY;
+        }
+
+        let mut key = [0u8; 16];
+        key[..8].copy_from_slice(&crkey.to_le_bytes());
+        key[8..].copy_from_slice(&prkey.to_le_bytes());
+        self.nvme_resv_command(NVME_CMD_RESV_ACQUIRE, nsid, cdw10, &key)
+    }
+
+    // release or clear a reservation
+    pub fn reservation_release(
+        &mut self,
+        nsid: u32,
+        action: NvmeReleaseAction,
+        rtype: NvmeReservationType,
+        iekey: bool,
+        crkey: u64,
+    ) -> NvmeResult<()> {
+        let mut cdw10 = action as u32 | (rtype as u32) << 8;
+        if iekey {
+            cdw10 |= NVME_RESV_IEKEY;
+        }
+
+        self.nvme_resv_command(NVME_CMD_RESV_RELEASE, nsid, cdw10, &crkey.to_le_bytes())
+    }
+
+    // read the reservation status of the namespace
+    // extended = true reports 128 bit host identifiers
+    pub fn reservation_report(
+        &mut self,
+        nsid: u32,
+        extended: bool,
+    ) -> NvmeResult<NvmeReservationStatus> {
+        self.nvme_resv_supported()?;
+        // prp1 + prp2 without a prp list
+        let len = PAGE_SIZE * 2;
+        let buf = self.io_buf.lock();
+
+        let mut cmd = NvmeCommonCommand::new();
+        cmd.opcode = NVME_CMD_RESV_REPORT;
+        cmd.nsid = nsid;
+        cmd.prp1 = buf.pa as u64;
+        cmd.prp2 = (buf.pa + PAGE_SIZE) as u64;
+        cmd.cdw10 = (len / 4 - 1) as u32;
+        if extended {
+            cmd.cdw11 = NVME_RESV_REPORT_EDS;
+        }
+        let cqe = self.submit_io_command(cmd)?;
+        NvmeError::from_status(cqe.status)?;
+
+        Ok(NvmeReservationStatus::parse(&buf.as_slice()[..len], extended))
+    }
+
+    // reservation key data is placed in the io data buffer
+    fn nvme_resv_command(&mut self, opcode: u8, nsid: u32, cdw10: u32, key: &[u8]) -> NvmeResult<()> {
+        self.nvme_resv_supported()?;
+        let mut buf = self.io_buf.lock();
+        buf.as_mut_slice()[..key.len()].copy_from_slice(key);
+
+        let mut cmd = NvmeCommonCommand::new();
+        cmd.opcode = opcode;
+        cmd.nsid = nsid;
+        cmd.prp1 = buf.pa as u64;
+        cmd.cdw10 = cdw10;
+        let cqe = self.submit_io_command(cmd)?;
+        NvmeError::from_status(cqe.status)
+    }
+
+    fn nvme_resv_supported(&mut self) -> NvmeResult<()> {
+        if self.ctrl_info()?.oncs & NVME_CTRL_ONCS_RESERVATIONS == 0 {
+            return Err(NvmeError::NotSupported);
+        }
+        Ok(())
+    }
+}
+
+
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // create a namespace of max_sectors blocks with lba format 0 and attach it to
+    // this controller, return the new nsid
+    pub fn alloc_ns(&mut self, max_sectors: u64) -> NvmeResult<u32> {
+        let id_ns = NvmeIdNs {
+            nsze: max_sectors,
+            ncap: max_sectors,
+            ..Default::default()
+        };
+        let nsid = self.create_ns(&id_ns)?;
+
+        let cntlid = self.ctrl_info()?.cntlid;
+        if let Err(e) = self.attach_ns(nsid, &[cntlid]) {
+            let _ = self.delete_ns(nsid);
+            return Err(e);
+        }
+        Ok(nsid)
+    }
+
+    // namespace management create, id_ns supplies nsze, ncap, flbas, dps and nmic
+    pub fn create_ns(&mut self, id_ns: &NvmeIdNs) -> NvmeResult<u32> {
+        if self.ctrl_info()?.oacs & NVME_CTRL_OACS_NS_MGMT == 0 {
+            return Err(NvmeError::NotSupported);
+        }
+
+        let admin_buf = self.admin_buf.clone();
+        let mut buf = admin_buf.lock();
+        id_ns.write_create_data(buf.as_mut_slice());
+
+        let mut cmd = NvmeCommonCommand::new();
+        cmd.opcode = NVME_ADMIN_NS_MGMT;
+        cmd.prp1 = buf.pa as u64;
+        cmd.cdw10 = NVME_NS_MGMT_SEL_CREATE;
+        let cqe = self.submit_sync_command(cmd)?;
+        NvmeError::from_status(cqe.status)?;
+
+        // dword0 of the completion is the nsid of the created namespace
+        Ok(cqe.result as u32)
+    }
+
+    // namespace management delete, NVME_NSID_ALL deletes every namespace
+    pub fn delete_ns(&mut self, nsid: u32) -> NvmeResult<()> {
+        let mut cmd = NvmeCommonCommand::new();
+        cmd.opcode = NVME_ADMIN_NS_MGMT;
+        cmd.nsid = nsid;
+        cmd.cdw10 = NVME_NS_MGMT_SEL_DELETE;
+        let cqe = self.submit_sync_command(cmd)?;
+        NvmeError::from_status(cqe.status)?;
+
+        if nsid == NVME_NSID_ALL {
+            self.namespaces.clear();
+        } else {
+            self.namespaces.remove(&nsid);
+        }
+        Ok(())
+    }
+
+    // attach the namespace to the controllers in ctrl_list
+    pub fn attach_ns(&mut self, nsid: u32, ctrl_list: &[u16]) -> NvmeResult<()> {
+        self.nvme_ns_attach(nsid, NVME_NS_ATTACH_SEL_ATTACH, ctrl_list)
+    }
+
+    // detach the namespace from the controllers in ctrl_list
+    pub fn detach_ns(&mut self, nsid: u32, ctrl_list: &[u16]) -> NvmeResult<()> {
+        self.nvme_ns_attach(nsid, NVME_NS_ATTACH_SEL_DETACH, ctrl_list)
+    }
+
+    // controller list: number of identifiers followed by the identifiers, all 16 bit
+    fn nvme_ns_attach(&mut self, nsid: u32, sel: u32, ctrl_list: &[u16]) -> NvmeResult<()> {
+        if ctrl_list.is_empty() || ctrl_list.len() > NVME_CTRL_LIST_MAX {
+            return Err(NvmeError::InvalidArgument);
+        }
+
+        let admin_buf = self.admin_buf.clone();
+        let mut buf = admin_buf.lock();
+        let data = buf.as_mut_slice();
+        data[..PAGE_SIZE].fill(0);
+        data[0..2].copy_from_slice(&(ctrl_list.len() as u16).to_le_bytes());
+        for (i, cntlid) in ctrl_list.iter().enumerate() {
+            data[2 + i * 2..4 + i * 2].copy_from_slice(&cntlid.to_le_bytes());
+        }
+
+        let mut cmd = NvmeCommonCommand::new();
+        cmd.opcode = NVME_ADMIN_NS_ATTACH;
+        cmd.nsid = nsid;
+        cmd.prp1 = buf.pa as u64;
+        cmd.cdw10 = sel;
+        let cqe = self.submit_sync_command(cmd)?;
+        NvmeError::from_status(cqe.status)?;
+
+        // identify data may change once the attachment changes
+        self.namespaces.remove(&nsid);
+        Ok(())
+    }
+}
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // low level format of the namespace (or all namespaces with NVME_NSID_ALL)
+    // lbaf: lba format index, ses: secure erase, pi: protection information type,
+    // pil: pi in the first bytes of metadata, mset: metadata as part of an extended lba
+    pub fn format_nvm(
+        &mut self,
+        nsid: u32,
+        lbaf: u8,
+        ses: NvmeSecureErase,
+        pi: u8,
+        pil: bool,
+        mset: bool,
+    ) -> NvmeResult<()> {
+        if lbaf > 63 || pi > 3 {
+            return Err(NvmeError::InvalidArgument);
+        }
+
+        let id_ctrl = self.ctrl_info()?;
+        if id_ctrl.oacs & NVME_CTRL_OACS_FORMAT == 0 {
+            return Err(NvmeError::NotSupported);
+        }
+        if ses == NvmeSecureErase::Crypto && id_ctrl.fna & NVME_CTRL_FNA_CRYPTO_ERASE == 0 {
+            return Err(NvmeError::NotSupported);
+        }
+
+        let mut cmd = NvmeCommonCommand::new();
+        cmd.opcode = NVME_ADMIN_FORMAT_NVM;
+        cmd.nsid = nsid;
+        cmd.cdw10 = nvme_format_cdw10(lbaf, ses, pi, pil, mset);
+        let cqe = self.submit_sync_command_timeout(cmd, NVME_FORMAT_TIMEOUT_US)?;
+        NvmeError::from_status(cqe.status)?;
+
+        // lba size and capacity may have changed, ns_info identifies them again
+        if nsid == NVME_NSID_ALL || id_ctrl.fna & NVME_CTRL_FNA_FORMAT_ALL != 0 {
+            self.namespaces.clear();
+        } else {
+            self.namespaces.remove(&nsid);
+        }
+        Ok(())
+    }
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // read buf.len() bytes of log page lid starting at byte offset into buf
+    // large logs are read in several commands using NUMDU and LPO. controllers without
+    // extended data support take a single command of 16KiB at most from offset 0
+    pub fn get_log_page(
+        &mut self,
+        lid: u8,
+        nsid: u32,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> NvmeResult<()> {
+        let extended = self.ctrl_info()?.lpa & NVME_CTRL_LPA_EXTENDED != 0;
+        let max = self.max_transfer_size()?;
+        let buf_va = buf.as_mut_ptr() as usize;
+        self.nvme_get_log_page(lid, nsid, offset, buf_va, buf.len(), D::virt_to_phys, max, extended)
+    }
+
+    // max is the transfer size limit, extended whether NUMDU and LPO are supported.
+    // to_phys turns an address inside buf into a bus address
+    #[allow(clippy::too_many_arguments)]
+    fn nvme_get_log_page(
+        &mut self,
+        lid: u8,
+        nsid: u32,
+        offset: u64,
+        buf: usize,
+        buf_len: usize,
+        to_phys: fn(usize) -> usize,
+        max: usize,
+        extended: bool,
+    ) -> NvmeResult<()> {
+        if offset % 4 != 0 || buf_len % 4 != 0 || buf_len == 0 {
+            return Err(NvmeError::InvalidArgument);
+        }
+        let max = nvme_log_chunk_max(max, extended);
+        if !extended && (offset != 0 || buf_len > max) {
+            return Err(NvmeError::NotSupported);
+        }
+
+        let mut done = 0;
+        while done < buf_len {
+            let len = (buf_len - done).min(max);
+            let numd = (len / 4 - 1) as u32;
+            let lpo = offset + done as u64;
+
+            let mut cmd = NvmeCommonCommand::new();
+            cmd.opcode = NVME_ADMIN_GET_LOG_PAGE;
+            cmd.nsid = nsid;
+            cmd.cdw10 = lid as u32 | (numd & 0xffff) << 16;
+            cmd.cdw11 = numd >> 16;
+            cmd.cdw12 = lpo as u32;
+            cmd.cdw13 = (lpo >> 32) as u32;
+            let cqe = self.nvme_submit_sync_prps(cmd, buf + done, len, to_phys)?;
+            NvmeError::from_status(cqe.status)?;
+
+            done += len;
+        }
+        Ok(())
+    }
+
+    // read the start of a log page through the admin data buffer
+    fn nvme_get_log(&mut self, lid: u8, nsid: u32, len: usize) -> NvmeResult<Vec<u8>> {
+        // identify before taking the buffer, identify needs it too
+        let extended = self.ctrl_info()?.lpa & NVME_CTRL_LPA_EXTENDED != 0;
+        let max = self.max_transfer_size()?;
+        if len > NVME_DATA_BUF_SIZE {
+            return Err(NvmeError::InvalidArgument);
+        }
+        let admin_buf = self.admin_buf.clone();
+        let buf = admin_buf.lock();
+        // the buffer may live in the controller memory buffer, address it by buf.pa
+        self.nvme_get_log_page(lid, nsid, 0, buf.pa, len, |pa| pa, max, extended)?;
+        Ok(buf.as_slice()[..len].to_vec())
+    }
+
+    // SMART / health information, NVME_NSID_ALL for the whole controller
+    pub fn smart_log(&mut self, nsid: u32) -> NvmeResult<NvmeSmartLog> {
+        let data = self.nvme_get_log(NVME_LOG_SMART, nsid, 512)?;
+        Ok(NvmeSmartLog::parse(&data))
+    }
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // start a sanitize operation, it runs in the background after the command completes
+    // owpass: overwrite pass count (0 = 16), ovrpat: overwrite pattern
+    pub fn sanitize(
+        &mut self,
+        action: NvmeSanitizeAction,
+        ause: bool,
+        owpass: u8,
+        oipbp: bool,
+        ovrpat: u32,
+        ndas: bool,
+    ) -> NvmeResult<()> {
+        let sanicap = self.ctrl_info()?.sanicap;
+        let supported = match action {
+            NvmeSanitizeAction::ExitFailureMode => true,
+            NvmeSanitizeAction::BlockErase => sanicap & NVME_CTRL_SANICAP_BLOCK != 0,
+            NvmeSanitizeAction::Overwrite => sanicap & NVME_CTRL_SANICAP_OVERWRITE != 0,
+            NvmeSanitizeAction::CryptoErase => sanicap & NVME_CTRL_SANICAP_CRYPTO != 0,
+        };
+        if !supported {
+            return Err(NvmeError::NotSupported);
+        }
+        if owpass > 15 {
+            return Err(NvmeError::InvalidArgument);
+        }
+
+        let mut cdw10 = action as u32 | (owpass as u32) << 4;
+        if ause {
+            cdw10 |= NVME_SANITIZE_AUSE;
+        }
+        if oipbp {
+            cdw10 |= NVME_SANITIZE_OIPBP;
+        }
+        if ndas {
+            cdw10 |= NVME_SANITIZE_NDAS;
+        }
+
+        let mut cmd = NvmeCommonCommand::new();
+        cmd.opcode = NVME_ADMIN_SANITIZE;
+        cmd.cdw10 = cdw10;
+        cmd.cdw11 = ovrpat;
+        let cqe = self.submit_sync_command(cmd)?;
+        NvmeError::from_status(cqe.status)
+    }
+
+    pub fn sanitize_status(&mut self) -> NvmeResult<NvmeSanitizeLog> {
+        let data = self.nvme_get_log(NVME_LOG_SANITIZE, NVME_NSID_ALL, 512)?;
+        Ok(NvmeSanitizeLog::parse(&data))
+    }
+
+    // poll the sanitize status log every interval_us, NVME_SANITIZE_POLL_MIN_US at least,
+    // until the operation finishes. progress is called with the completion percentage on
+    // every poll
+    pub fn sanitize_wait(
+        &mut self,
+        interval_us: u64,
+        mut progress: impl FnMut(u8),
+    ) -> NvmeResult<NvmeSanitizeLog> {
+        loop {
+            let log = self.sanitize_status()?;
+            progress(log.progress_percent());
+            match log.status() {
+                NvmeSanitizeStatus::InProgress => {
+                    T::delay_us(interval_us.max(NVME_SANITIZE_POLL_MIN_US))
+                }
+                NvmeSanitizeStatus::Failed => return Err(NvmeError::SanitizeFailed),
+                _ => return Ok(log),
+            }
+        }
+    }
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // download part of a firmware image starting at byte offset, the chunk is split
+    // into transfers that respect FWUG and MDTS. a chunk that is not dword aligned in
+    // memory is copied through a dma bounce buffer
+    pub fn firmware_download(&mut self, offset: u32, chunk: &[u8]) -> NvmeResult<()> {
+        if offset % 4 != 0 || chunk.len() % 4 != 0 {
+            return Err(NvmeError::InvalidArgument);
+        }
+
+        let id_ctrl = self.ctrl_info()?;
+        if id_ctrl.oacs & NVME_CTRL_OACS_FW == 0 {
+            return Err(NvmeError::NotSupported);
+        }
+
+        let mut max = self.max_transfer_size()?;
+        // fwug is in 4KiB units, 0 = no information, 0xff = no restriction
+        if id_ctrl.fwug != 0 && id_ctrl.fwug != 0xff {
+            let granularity = id_ctrl.fwug as usize * 4096;
+            if offset as usize % granularity != 0 {
+                return Err(NvmeError::InvalidArgument);
+            }
+            max = max / granularity * granularity;
+            if max == 0 {
+                return Err(NvmeError::NotSupported);
+            }
+        }
+
+        let bounce_len = if chunk.as_ptr() as usize % 4 != 0 { chunk.len().min(max) } else { 0 };
+        let bounce_va = if bounce_len > 0 { D::dma_alloc(bounce_len) } else { 0 };
+
+        let mut result = Ok(());
+        let mut done = 0;
+        while done < chunk.len() {
+            let len = (chunk.len() - done).min(max);
+
+            let mut cmd = NvmeCommonCommand::new();
+            cmd.opcode = NVME_ADMIN_FW_DOWNLOAD;
+            cmd.cdw10 = (len / 4 - 1) as u32;
+            cmd.cdw11 = (offset as usize + done) as u32 / 4;
+            let buf_va = if bounce_len > 0 {
+                let bounce = unsafe { slice::from_raw_parts_mut(bounce_va as *mut u8, len) };
+                bounce.copy_from_slice(&chunk[done..done + len]);
+                bounce_va
+            } else {
+                chunk[done..].as_ptr() as usize
+            };
+            result = self
+                .submit_sync_command_buf(cmd, buf_va, len)
+                .and_then(|cqe| NvmeError::from_status(cqe.status));
+            if result.is_err() {
+                break;
+            }
+
+            done += len;
+        }
+
+        if bounce_len > 0 {
+            D::dma_dealloc(bounce_va, bounce_len);
+        }
+        result
+    }
+
+    // commit the downloaded image to slot (0 = controller selects the slot)
+    // firmware that needs a reset to activate completes with a command specific
+    // status, see NVME_SC_FW_NEEDS_*
+    pub fn firmware_commit(&mut self, slot: u8, action: NvmeCommitAction) -> NvmeResult<()> {
+        if slot > 7 {
+            return Err(NvmeError::InvalidArgument);
+        }
+
+        let frmw = self.ctrl_info()?.frmw;
+        if action == NvmeCommitAction::ActivateNoReset && frmw & NVME_CTRL_FRMW_NO_RESET == 0 {
+            return Err(NvmeError::NotSupported);
+        }
+
+        let mut cmd = NvmeCommonCommand::new();
+        cmd.opcode = NVME_ADMIN_FW_COMMIT;
+        cmd.cdw10 = slot as u32 | (action as u32) << 3;
+        let cqe = self.submit_sync_command(cmd)?;
+        NvmeError::from_status(cqe.status)
+    }
+
+    pub fn firmware_slot_info(&mut self) -> NvmeResult<NvmeFwSlotLog> {
+        let data = self.nvme_get_log(NVME_LOG_FW_SLOT, NVME_NSID_ALL, 512)?;
+        Ok(NvmeFwSlotLog::parse(&data))
+    }
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // error information log, newest entry first, empty entries are skipped
+    pub fn error_log(&mut self) -> NvmeResult<Vec<NvmeErrorLogEntry>> {
+        let max_entries = NVME_DATA_BUF_SIZE / NvmeErrorLogEntry::SIZE;
+        let entries = (self.ctrl_info()?.elpe as usize + 1).min(max_entries);
+        let data = self.nvme_get_log(NVME_LOG_ERROR, NVME_NSID_ALL, entries * NvmeErrorLogEntry::SIZE)?;
+
+        Ok(data
+            .chunks_exact(NvmeErrorLogEntry::SIZE)
+            .map(NvmeErrorLogEntry::parse)
+            .filter(|entry| entry.error_count != 0)
+            .collect())
+    }
+
+    // recently failed commands of all queues
+    pub fn failed_commands(&self) -> Vec<NvmeFailedCommand> {
+        let mut failed: Vec<NvmeFailedCommand> =
+            self.admin_queue.lock().failed.iter().copied().collect();
+        for io_queue in self.io_queues.iter() {
+            failed.extend(io_queue.lock().failed.iter().copied());
+        }
+        failed
+    }
+
+    // find the failed command an error log entry refers to
+    pub fn correlate_error(&self, entry: &NvmeErrorLogEntry) -> Option<NvmeFailedCommand> {
+        let queue = if entry.sqid == 0 {
+            &self.admin_queue
+        } else {
+            self.io_queues.iter().find(|q| q.lock().qid == entry.sqid as usize)?
+        };
+        let queue = queue.lock();
+        // command ids are reused, the most recent failure is the one the entry describes
+        queue.failed.iter().rev().find(|f| f.cmd.command_id == entry.cmdid).copied()
+    }
+
+    // error log entry describing a failed command, if the controller logged one
+    pub fn explain_failure(
+        &mut self,
+        failed: &NvmeFailedCommand,
+    ) -> NvmeResult<Option<NvmeErrorLogEntry>> {
+        Ok(self
+            .error_log()?
+            .into_iter()
+            .find(|entry| entry.sqid == failed.sqid && entry.cmdid == failed.cmd.command_id))
+    }
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // enable smart / health warnings and the notices the controller supports, keep
+    // asynchronous event requests outstanding and deliver events to handler
+    pub fn enable_async_events(&mut self, handler: Box<dyn NvmeEventHandler>) -> NvmeResult<()> {
+        let id_ctrl = self.ctrl_info()?;
+        let config = NVME_AEN_CFG_SMART_MASK | (id_ctrl.oaes & NVME_AEN_CFG_NOTICE_MASK);
+        self.set_async_event_config(config, false)?;
+        // namespace attribute changed notices are compared with this snapshot
+        if self.active_ns.is_none() {
+            self.rescan_namespaces()?;
+        }
+
+        self.event_handler = Some(handler);
+
+        let outstanding = {
+            let admin_queue = self.admin_queue.lock();
+            admin_queue
+                .inflight
+                .values()
+                .filter(|inflight| inflight.cmd.opcode == NVME_ADMIN_ASYNC_EVENT)
+                .count()
+        };
+        let limit = (id_ctrl.aerl as usize + 1).min(NVME_NR_AEN_COMMANDS);
+        for _ in outstanding..limit {
+            self.submit_async_event();
+        }
+        Ok(())
+    }
+
+    // asynchronous event requests complete only when an event occurs, do not wait
+    fn submit_async_event(&self) {
+        let mut cmd = NvmeCommonCommand::new();
+        cmd.opcode = NVME_ADMIN_ASYNC_EVENT;
+        let mut admin_queue = self.admin_queue.lock();
+        self.send_command(&mut admin_queue, cmd);
+    }
+
+    // handle completed asynchronous event requests: read (and so clear) the log page
+    // of the event, hand the event to the handler and re-arm the request
+    // call it from the admin queue interrupt or periodically
+    pub fn process_async_events(&mut self) -> NvmeResult<()> {
+        let completions: Vec<NvmeCompletion> = {
+            let mut admin_queue = self.admin_queue.lock();
+            while self.nvme_cqe_pending(&mut admin_queue) {
+                self.nvme_reap_cqe(&mut admin_queue);
+            }
+            admin_queue.async_events.drain(..).collect()
+        };
+
+        for cqe in completions {
+            // aborted, or the controller refuses more requests: do not re-arm
+            if NvmeError::from_status(cqe.status).is_err() {
+                continue;
+            }
+
+            let event = NvmeAsyncEvent::from_result(cqe.result as u32);
+            let log = self.nvme_read_event_log(&event)?;
+            let changed = if event.event_type == NVME_AER_TYPE_NOTICE
+                && event.info == NVME_AER_NOTICE_NS_CHANGED
+            {
+                Some(NvmeChangedNsList::parse(&log))
+            } else {
+                None
+            };
+
+            if let Some(handler) = self.event_handler.as_mut() {
+                handler.handle_event(&event, &log);
+            }
+            self.submit_async_event();
+
+            match changed {
+                Some(list) if list.overflow || self.active_ns.is_none() => {
+                    self.rescan_namespaces()?
+                }
+                Some(list) => self.nvme_update_namespaces(&list.nsids)?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    // reading the log page with RAE cleared acknowledges the event
+    fn nvme_read_event_log(&mut self, event: &NvmeAsyncEvent) -> NvmeResult<Vec<u8>> {
+        let len = match event.lid {
+            NVME_LOG_ERROR => {
+                let entries = (self.ctrl_info()?.elpe as usize + 1).min(64);
+                entries * NvmeErrorLogEntry::SIZE
+            }
+            NVME_LOG_CHANGED_NS => PAGE_SIZE,
+            _ => 512,
+        };
+        self.nvme_get_log(event.lid, NVME_NSID_ALL, len)
+    }
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // nsids of all active namespaces, in increasing order
+    pub fn active_ns_list(&mut self) -> NvmeResult<Vec<u32>> {
+        self.nvme_active_ns_list(NVME_ID_CNS_NS_ACTIVE_LIST, NVME_CSI_NVM)
+    }
+
+    // active namespaces of the i/o command set csi
+    pub fn active_ns_list_csi(&mut self, csi: u8) -> NvmeResult<Vec<u32>> {
+        self.nvme_active_ns_list(NVME_ID_CNS_CS_NS_ACTIVE_LIST, csi)
+    }
+
+    fn nvme_active_ns_list(&mut self, cns: u8, csi: u8) -> NvmeResult<Vec<u32>> {
+        let mut nsids = Vec::new();
+        let mut start = 0;
+        loop {
+            // up to 1024 active nsids greater than start, zero terminated
+            let data = self.identify_csi(start, cns, csi)?;
+            let count = nsids.len();
+            for entry in data.chunks_exact(4) {
+                match le32(entry, 0) {
+                    0 => break,
+                    nsid => nsids.push(nsid),
+                }
+            }
+            if nsids.len() - count < 1024 {
+                return Ok(nsids);
+            }
+            start = *nsids.last().unwrap();
+        }
+    }
+
+    // re-identify every active or previously active namespace and report changes to the
+    // event handler. the first call only records the active namespaces
+    pub fn rescan_namespaces(&mut self) -> NvmeResult<()> {
+        let mut nsids = self.active_ns_list()?;
+        for nsid in self.active_ns.iter().flat_map(|active| active.keys()) {
+            if !nsids.contains(nsid) {
+                nsids.push(*nsid);
+            }
+        }
+        self.nvme_update_namespaces(&nsids)
+    }
+
+    fn nvme_update_namespaces(&mut self, nsids: &[u32]) -> NvmeResult<()> {
+        // the first update has nothing to compare with
+        let report = self.active_ns.is_some();
+        let mut active = self.active_ns.take().unwrap_or_default();
+        let mut result = Ok(());
+        for &nsid in nsids {
+            // inactive namespaces identify as all zero or fail with invalid namespace
+            let new = match self.identify(nsid, NVME_ID_CNS_NS) {
+                Ok(data) => Some(NvmeIdNs::parse(&data)).filter(|id_ns| id_ns.nsze != 0),
+                Err(NvmeError::CommandFailed { .. }) => None,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            let old = match new {
+                Some(id_ns) => {
+                    self.namespaces.insert(nsid, id_ns);
+                    active.insert(nsid, id_ns)
+                }
+                None => {
+                    self.namespaces.remove(&nsid);
+                    active.remove(&nsid)
+                }
+            };
+
+            let change = NvmeNsChange::between(old.as_ref(), new.as_ref()).filter(|_| report);
+            if let (Some(change), Some(handler)) = (change, self.event_handler.as_mut()) {
+                handler.namespace_changed(nsid, change);
+            }
+        }
+        self.active_ns = Some(active);
+        result
+    }
+}
+
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    pub fn power_states(&mut self) -> NvmeResult<Vec<NvmePowerState>> {
+        Ok(self.ctrl_info()?.power_states().to_vec())
+    }
+
+    pub fn power_state(&mut self) -> NvmeResult<u8> {
+        Ok(self.get_power_mgmt(NvmeFeatureSel::Current)?.ps)
+    }
+
+    // the workload hint is left unchanged
+    pub fn set_power_state(&mut self, ps: u8) -> NvmeResult<()> {
+        if ps > self.ctrl_info()?.npss {
+            return Err(NvmeError::InvalidArgument);
+        }
+        let mut pm = self.get_power_mgmt(NvmeFeatureSel::Current)?;
+        pm.ps = ps;
+        self.set_power_mgmt(&pm, false)
+    }
+
+    // return whether apst is enabled and the transition table
+    pub fn get_apst(&mut self, sel: NvmeFeatureSel) -> NvmeResult<(bool, NvmeApstTable)> {
+        self.apst_supported()?;
+        let (result, data) =
+            self.nvme_get_features(NVME_FEAT_AUTO_PST, 0, sel, 0, NvmeApstTable::SIZE)?;
+        Ok((result & 0x1 != 0, NvmeApstTable::parse(&data)))
+    }
+
+    pub fn set_apst(&mut self, enable: bool, table: &NvmeApstTable, save: bool) -> NvmeResult<()> {
+        self.apst_supported()?;
+        let npss = self.ctrl_info()?.npss;
+        for ps in 0..table.entries.len() as u8 {
+            if let Some((target, _)) = table.transition(ps) {
+                if ps > npss || target > npss {
+                    return Err(NvmeError::InvalidArgument);
+                }
+            }
+        }
+
+        let mut data = [0u8; NvmeApstTable::SIZE];
+        table.write_to(&mut data);
+        self.nvme_set_features(NVME_FEAT_AUTO_PST, 0, enable as u32, save, Some(&data))?;
+        Ok(())
+    }
+
+    // build the table from the power state descriptors and enable apst,
+    // max_exit_lat_us bounds the latency a command may see when the controller wakes up
+    pub fn enable_apst(&mut self, max_exit_lat_us: u32) -> NvmeResult<NvmeApstTable> {
+        let id_ctrl = self.ctrl_info()?;
+        let table = NvmeApstTable::from_power_states(id_ctrl.power_states(), max_exit_lat_us);
+        // no usable non-operational state
+        if table == NvmeApstTable::default() {
+            self.disable_apst()?;
+        } else {
+            self.set_apst(true, &table, false)?;
+        }
+        Ok(table)
+    }
+
+    pub fn disable_apst(&mut self) -> NvmeResult<()> {
+        self.set_apst(false, &NvmeApstTable::default(), false)
+    }
+
+    fn apst_supported(&mut self) -> NvmeResult<()> {
+        if self.ctrl_info()?.apsta & NVME_CTRL_APSTA_APST == 0 {
+            return Err(NvmeError::NotSupported);
+        }
+        Ok(())
+    }
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // 1. wait for outstanding io commands (normal only)
+    // 2. delete io queues (normal only)
+    // 3. set CC.SHN and wait for CSTS.SHST to report shutdown complete
+    pub fn shutdown(&mut self, kind: NvmeShutdown) -> NvmeResult<()> {
+        if self.shut_down {
+            return Ok(());
+        }
+
+        // use the cached identify data, a dying controller may not answer identify
+        let timeout_us = nvme_shutdown_timeout(self.id_ctrl.map_or(0, |id_ctrl| id_ctrl.rtd3e));
+
+        I::disable_irq(self.irq);
+
+        // a failed controller cannot process the queue deletion. the controller is shut
+        // down even if a deletion fails, the error is returned afterwards
+        let mut result = Ok(());
+        if kind == NvmeShutdown::Normal && !self.controller_fatal() {
+            for io_queue in self.io_queues.clone() {
+                let mut io_queue = io_queue.lock();
+                // deleting the sq aborts whatever is still outstanding
+                let _ = self.nvme_drain_queue(&mut io_queue, timeout_us);
+                let qid = io_queue.qid as u16;
+                drop(io_queue);
+                result = self
+                    .nvme_delete_queue(NVME_ADMIN_DELETE_SQ, qid, timeout_us)
+                    .and_then(|_| self.nvme_delete_queue(NVME_ADMIN_DELETE_CQ, qid, timeout_us));
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+
+        let shn = match kind {
+            NvmeShutdown::Normal => NVME_CC_SHN_NORMAL,
+            NvmeShutdown::Abrupt => NVME_CC_SHN_ABRUPT,
+        };
+        let cc = unsafe { read_volatile((self.bar + NVME_REG_CC) as *const u32) };
+        unsafe {
+            write_volatile((self.bar + NVME_REG_CC) as *mut u32, (cc & !NVME_CC_SHN_MASK) | shn)
+        }
+        self.shut_down = true;
+
+        let start = T::current_time_us();
+        let shst = loop {
+            let csts = unsafe { read_volatile((self.bar + NVME_REG_CSTS) as *const u32) };
+            if csts & NVME_CSTS_SHST_MASK == NVME_CSTS_SHST_CMPLT {
+                break Ok(());
+            }
+            if T::current_time_us() - start > timeout_us {
+                break Err(NvmeError::Timeout);
+            }
+        };
+        result.and(shst)
+    }
+
+    // retire completions until no command is outstanding on the queue
+    fn nvme_drain_queue(
+        &self,
+        nvmeq: &mut MutexGuard<NvmeQueue<D>>,
+        timeout_us: u64,
+    ) -> NvmeResult<()> {
+        let start = T::current_time_us();
+        while !nvmeq.inflight.is_empty() {
+            while !self.nvme_cqe_pending(nvmeq) {
+                if self.controller_fatal() {
+                    return Err(NvmeError::ControllerFatal);
+                }
+                if T::current_time_us() - start > timeout_us {
+                    return Err(NvmeError::Timeout);
+                }
+            }
+            self.nvme_reap_cqe(nvmeq);
+        }
+        Ok(())
+    }
+
+    fn nvme_delete_queue(&mut self, opcode: u8, qid: u16, timeout_us: u64) -> NvmeResult<()> {
+        let cmd = NvmeDeleteQueue::new(opcode, qid);
+        let common_cmd = unsafe { core::mem::transmute(cmd) };
+        let cqe = self.submit_sync_command_timeout(common_cmd, timeout_us)?;
+        NvmeError::from_status(cqe.status)
+    }
+}
+
+// time to wait for the shutdown to complete, RTD3E in us, 0 when not reported
+fn nvme_shutdown_timeout(rtd3e: u32) -> u64 {
+    (rtd3e as u64).clamp(NVME_SHUTDOWN_TIMEOUT_US, NVME_SHUTDOWN_TIMEOUT_MAX_US)
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> Drop for NvmeInterface<D, I, T> {
+    fn drop(&mut self) {
+        let _ = self.shutdown(NvmeShutdown::Normal);
+    }
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    pub fn set_recovery_policy(&mut self, policy: NvmeRecoveryPolicy) {
+        self.recovery_policy = policy;
+    }
+
+    pub fn controller_fatal(&self) -> bool {
+        nvme_csts_fatal(unsafe { read_volatile((self.bar + NVME_REG_CSTS) as *const u32) })
+    }
+
+    // reset a controller in fatal status
+    // 1. disable the controller and wait for CSTS.RDY to clear
+    // 2. rewind every queue, keep the commands that were in flight
+    // 3. configure the admin queue and create the io queues again
+    // 4. resubmit the commands in flight, or fail them under NvmeRecoveryPolicy::FailInflight
+    //    and NvmeRecoveryPolicy::Manual. asynchronous event requests are always resubmitted
+    pub fn recover(&self) -> NvmeResult<()> {
+        let _recovery = self.recovery.lock();
+        // another caller got here first
+        if !self.controller_fatal() {
+            self.fatal_pending.store(false, Ordering::Release);
+            return Ok(());
+        }
+        self.nvme_reset()?;
+        self.fatal_pending.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    // recover from a fatal status handle_irq found, unless the policy leaves it to the user
+    fn nvme_recover_pending(&self) -> NvmeResult<()> {
+        if self.fatal_pending.load(Ordering::Acquire)
+            && self.recovery_policy != NvmeRecoveryPolicy::Manual
+        {
+            self.recover()?;
+        }
+        Ok(())
+    }
+
+    // same as recover, for a controller that is not in fatal status
+    pub fn reset_controller(&self) -> NvmeResult<()> {
+        let _recovery = self.recovery.lock();
+        self.nvme_reset()
+    }
+
+    pub fn set_pci_reset(&mut self, pci_reset: Box<dyn NvmePciReset>) {
+        *self.pci_reset.lock() = Some(pci_reset);
+    }
+
+    // reset the whole nvm subsystem by writing "NVMe" to NSSR, then initialize again
+    pub fn subsystem_reset(&self) -> NvmeResult<()> {
+        if self.nvme_read_cap() & NVME_CAP_NSSRS == 0 {
+            return Err(NvmeError::NotSupported);
+        }
+        let _recovery = self.recovery.lock();
+        if self.shut_down {
+            return Err(NvmeError::ControllerFatal);
+        }
+
+        unsafe { write_volatile((self.bar + NVME_REG_NSSR) as *mut u32, NVME_NSSR_MAGIC) }
+        // the pcie link may go down for a while
+        if self.nvme_wait_not_ready().is_err() {
+            self.nvme_function_reset()?;
+        }
+        self.nvme_reinit()
+    }
+
+    // pcie function level reset through the platform hook, then initialize again
+    pub fn function_level_reset(&self) -> NvmeResult<()> {
+        let _recovery = self.recovery.lock();
+        if self.shut_down {
+            return Err(NvmeError::ControllerFatal);
+        }
+        self.nvme_function_reset()?;
+        self.nvme_reinit()
+    }
+
+    fn nvme_function_reset(&self) -> NvmeResult<()> {
+        match self.pci_reset.lock().as_mut() {
+            Some(pci_reset) => {
+                if !pci_reset.function_level_reset() {
+                    return Err(NvmeError::ControllerFatal);
+                }
+            }
+            None => return Err(NvmeError::NotSupported),
+        }
+        self.nvme_wait_not_ready()
+    }
+
+    fn nvme_reset(&self) -> NvmeResult<()> {
+        if self.shut_down {
+            return Err(NvmeError::ControllerFatal);
+        }
+
+        let cc = unsafe { read_volatile((self.bar + NVME_REG_CC) as *const u32) };
+        unsafe { write_volatile((self.bar + NVME_REG_CC) as *mut u32, cc & !NVME_CC_ENABLE) }
+        // an unresponsive controller gets a function level reset when the platform can do it
+        if self.nvme_wait_not_ready().is_err() {
+            self.nvme_function_reset()?;
+        }
+        self.nvme_reinit()
+    }
+
+    // wait for CSTS.RDY to clear, CSTS reads all ones while the device is unreachable
+    fn nvme_wait_not_ready(&self) -> NvmeResult<()> {
+        let timeout_us = nvme_cap_timeout_us(self.nvme_read_cap());
+        let start = T::current_time_us();
+        loop {
+            let csts = unsafe { read_volatile((self.bar + NVME_REG_CSTS) as *const u32) };
+            if csts != u32::MAX && csts & NVME_CSTS_RDY == 0 {
+                return Ok(());
+            }
+            if T::current_time_us() - start > timeout_us {
+                return Err(NvmeError::Timeout);
+            }
+        }
+    }
+
+    // bring up a controller that has been reset, then resubmit or fail the commands that
+    // were in flight
+    fn nvme_reinit(&self) -> NvmeResult<()> {
+        self.nvme_enable_cmb_space();
+
+        let queues: Vec<&Mutex<NvmeQueue<D>>> = core::iter::once(&*self.admin_queue)
+            .chain(self.io_queues.iter().map(|q| &**q))
+            .collect();
+        let inflight: Vec<BTreeMap<u16, NvmeInflight>> = queues
+            .iter()
+            .map(|queue| {
+                let mut nvmeq = queue.lock();
+                nvmeq.reset();
+                core::mem::take(&mut nvmeq.inflight)
+            })
+            .collect();
+
+        self.nvme_configure_admin_queue();
+        // the number of queues does not survive the reset
+        self.nvme_set_queue_count(self.io_queues.len() as u16)?;
+        self.nvme_alloc_io_queue()?;
+        if self.controller_fatal() {
+            return Err(NvmeError::ControllerFatal);
+        }
+        // the doorbell buffer config does not survive the reset
+        let _ = self.nvme_dbbuf_set();
+
+        let resubmit = self.recovery_policy == NvmeRecoveryPolicy::Resubmit;
+        for (queue, commands) in queues.iter().zip(inflight) {
+            let mut nvmeq = queue.lock();
+            for NvmeInflight { cmd, waited, .. } in commands.into_values() {
+                if resubmit || (nvmeq.qid == 0 && cmd.opcode == NVME_ADMIN_ASYNC_EVENT) {
+                    self.nvme_queue_command(&mut nvmeq, cmd);
+                    if let Some(inflight) = nvmeq.inflight.get_mut(&cmd.command_id) {
+                        inflight.waited = waited;
+                    }
+                } else {
+                    nvmeq.fail_command(cmd, NVME_STATUS_HOST_ABORTED);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// CAP.TO, in 500ms units. CAP itself is unreadable while the device is gone, wait for the
+// longest timeout then
+fn nvme_cap_timeout_us(cap: u64) -> u64 {
+    let to = if cap == u64::MAX { 0xff } else { (cap >> 24) & 0xff };
+    to.max(1) * 500 * 1000
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // handle commands past their deadline that nobody waits for, e.g. after
+    // nvme_wait_command_timeout gave up. call it periodically
+    pub fn check_timeouts(&self) -> NvmeResult<()> {
+        self.nvme_recover_pending()?;
+        let now = T::current_time_us();
+        let queues = core::iter::once(&self.admin_queue).chain(self.io_queues.iter());
+        for queue in queues {
+            let expired: Vec<u16> = queue
+                .lock()
+                .inflight
+                .iter()
+                .filter(|(_, inflight)| inflight.deadline_us < now)
+                .map(|(cid, _)| *cid)
+                .collect();
+            for cid in expired {
+                self.nvme_timeout(queue, cid)?;
+            }
+        }
+        Ok(())
+    }
+
+    // like linux nvme_timeout: the first time a command passes its deadline it is aborted
+    // and given another NVME_ADMIN_TIMEOUT_US. if the abort fails or the command is still
+    // stuck, it is failed and the controller is reset
+    fn nvme_timeout(&self, queue: &Mutex<NvmeQueue<D>>, cid: u16) -> NvmeResult<()> {
+        let (sqid, aborted) = {
+            let mut nvmeq = queue.lock();
+            let sqid = nvmeq.qid as u16;
+            match nvmeq.inflight.get_mut(&cid) {
+                Some(inflight) => {
+                    let aborted = inflight.aborted;
+                    inflight.aborted = true;
+                    inflight.deadline_us =
+                        T::current_time_us().saturating_add(NVME_ADMIN_TIMEOUT_US);
+                    (sqid, aborted)
+                }
+                // completed in the meantime
+                None => return Ok(()),
+            }
+        };
+
+        if !aborted && self.nvme_abort(sqid, cid).is_ok() {
+            return Ok(());
+        }
+
+        {
+            let mut nvmeq = queue.lock();
+            if let Some(inflight) = nvmeq.inflight.remove(&cid) {
+                nvmeq.fail_command(inflight.cmd, NVME_STATUS_HOST_ABORTED);
+            }
+        }
+        self.reset_controller()
+    }
+
+    // the aborted command completes with NVME_SC_ABORT_REQ, or not at all when the
+    // controller could not abort it
+    fn nvme_abort(&self, sqid: u16, cid: u16) -> NvmeResult<()> {
+        let mut cmd = NvmeCommonCommand::new();
+        cmd.opcode = NVME_ADMIN_ABORT;
+        cmd.cdw10 = sqid as u32 | (cid as u32) << 16;
+
+        let mut admin_queue = self.admin_queue.lock();
+        let abort_cid = self.send_command(&mut admin_queue, cmd);
+        let result = self.nvme_wait_cid(&mut admin_queue, abort_cid, None);
+        if result.is_err() {
+            // never resubmit the abort after a reset
+            admin_queue.inflight.remove(&abort_cid);
+            admin_queue.release_prp_list(abort_cid);
+        }
+        NvmeError::from_status(result?.status)
+    }
+}
+
+// CSTS reads all ones once the device is gone
+fn nvme_csts_fatal(csts: u32) -> bool {
+    csts == u32::MAX || csts & NVME_CSTS_CFS != 0
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // CMBLOC / CMBSZ, None when the controller has no controller memory buffer. since 1.4
+    // both registers read 0 until CMBMSC.CRE is set, which enable_cmb does
+    pub fn cmb_info(&self) -> Option<NvmeCmbInfo> {
+        let cmbloc = unsafe { read_volatile((self.bar + NVME_REG_CMBLOC) as *const u32) };
+        let cmbsz = unsafe { read_volatile((self.bar + NVME_REG_CMBSZ) as *const u32) };
+        if cmbsz == 0 {
+            return None;
+        }
+        Some(NvmeCmbInfo::from_regs(cmbloc, cmbsz))
+    }
+
+    // bar_va / bar_pa map the pci bar named by NvmeCmbInfo::bir. the cmb is carved in order:
+    // io submission queues, prp lists, data buffers. io queues should be idle, their
+    // submission queues are deleted and created again in the cmb
+    pub fn enable_cmb(
+        &mut self,
+        bar_va: usize,
+        bar_pa: usize,
+        usage: NvmeCmbUsage,
+    ) -> NvmeResult<NvmeCmbInfo> {
+        if self.nvme_version() >= 0x10400 {
+            let cmbmsc = self.nvme_read_cmbmsc();
+            self.nvme_write_cmbmsc(cmbmsc | NVME_CMBMSC_CRE);
+        }
+        let info = self.cmb_info().ok_or(NvmeError::NotSupported)?;
+
+        let mut required = 0;
+        if usage.sq {
+            required |= NVME_CMBSZ_SQS;
+        }
+        if usage.prp_lists {
+            required |= NVME_CMBSZ_LISTS;
+        }
+        if usage.data {
+            required |= NVME_CMBSZ_RDS | NVME_CMBSZ_WDS;
+        }
+        if info.flags & required != required {
+            return Err(NvmeError::NotSupported);
+        }
+
+        let io_queues = self.io_queues.clone();
+        let queues: Vec<&Arc<Mutex<NvmeQueue<D>>>> =
+            core::iter::once(&self.admin_queue).chain(io_queues.iter()).collect();
+        let mut needed = 0;
+        if usage.sq {
+            needed += io_queues.iter().map(|q| q.lock().q_depth * 64).sum::<usize>();
+        }
+        if usage.prp_lists {
+            needed += queues.len() * PAGE_SIZE;
+        }
+        if usage.data {
+            needed += 2 * NVME_DATA_BUF_SIZE;
+        }
+        if needed as u64 > info.size {
+            return Err(NvmeError::InvalidArgument);
+        }
+
+        let cmb_va = bar_va + info.offset as usize;
+        let cmb_pa = bar_pa + info.offset as usize;
+        self.cmb_cba = Some(cmb_pa as u64);
+        self.nvme_enable_cmb_space();
+
+        let mut offset = 0;
+        if usage.sq {
+            for (io_queue, prio) in io_queues.iter().zip(NVME_IO_QUEUE_PRIOS) {
+                // keep the queue locked so nothing is submitted while it is moved
+                let mut nvmeq = io_queue.lock();
+                self.nvme_drain_queue(&mut nvmeq, NVME_IO_TIMEOUT_US)?;
+
+                let qid = nvmeq.qid as u16;
+                let cmd = NvmeDeleteQueue::new(NVME_ADMIN_DELETE_SQ, qid);
+                self.nvme_admin_command(unsafe { core::mem::transmute(cmd) })?;
+
+                nvmeq.set_sq(cmb_va + offset, cmb_pa + offset);
+                offset += nvmeq.q_depth * 64;
+                self.nvme_create_sq(qid, nvmeq.sq_pa, nvmeq.q_depth as u16, prio)?;
+            }
+        }
+        if usage.prp_lists {
+            for queue in queues.iter() {
+                queue.lock().set_prp_list(cmb_va + offset, cmb_pa + offset);
+                offset += PAGE_SIZE;
+            }
+        }
+        if usage.data {
+            for buf in [&self.admin_buf, &self.io_buf] {
+                buf.lock().relocate(cmb_va + offset, cmb_pa + offset);
+                offset += NVME_DATA_BUF_SIZE;
+            }
+        }
+        Ok(info)
+    }
+
+    // program CMBMSC with the controller base address, again after every controller reset
+    fn nvme_enable_cmb_space(&self) {
+        if let Some(cba) = self.cmb_cba {
+            if self.nvme_version() >= 0x10400 {
+                self.nvme_write_cmbmsc((cba & !0xfff) | NVME_CMBMSC_CRE | NVME_CMBMSC_CMSE);
+            }
+        }
+    }
+
+    fn nvme_version(&self) -> u32 {
+        unsafe { read_volatile((self.bar + NVME_REG_VS) as *const u32) }
+    }
+
+    fn nvme_read_cmbmsc(&self) -> u64 {
+        let low = unsafe { read_volatile((self.bar + NVME_REG_CMBMSC) as *const u32) };
+        let high = unsafe { read_volatile((self.bar + NVME_REG_CMBMSC + 4) as *const u32) };
+        (high as u64) << 32 | low as u64
+    }
+
+    // high half first, the enable bits are in the low half
+    fn nvme_write_cmbmsc(&self, cmbmsc: u64) {
+        unsafe {
+            write_volatile((self.bar + NVME_REG_CMBMSC + 4) as *mut u32, (cmbmsc >> 32) as u32);
+            write_volatile((self.bar + NVME_REG_CMBMSC) as *mut u32, cmbmsc as u32);
+        }
+    }
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // None when the controller has no persistent memory region
+    pub fn pmr_info(&self) -> Option<NvmePmrInfo> {
+        let pmrcap = unsafe { read_volatile((self.bar + NVME_REG_PMRCAP) as *const u32) };
+        if self.nvme_version() < 0x10400 || pmrcap == 0 {
+            return None;
+        }
+        let pmrebs = unsafe { read_volatile((self.bar + NVME_REG_PMREBS) as *const u32) };
+        let pmrswtp = unsafe { read_volatile((self.bar + NVME_REG_PMRSWTP) as *const u32) };
+        Some(NvmePmrInfo::from_regs(pmrcap, pmrebs, pmrswtp))
+    }
+
+    // bar_va / size map the pci bar named by NvmePmrInfo::bir
+    pub fn enable_pmr(&mut self, bar_va: usize, size: usize) -> NvmeResult<PmrRegion> {
+        let info = self.pmr_info().ok_or(NvmeError::NotSupported)?;
+        if !info.rds || !info.wds {
+            return Err(NvmeError::NotSupported);
+        }
+
+        unsafe { write_volatile((self.bar + NVME_REG_PMRCTL) as *mut u32, NVME_PMRCTL_EN) }
+        self.nvme_wait_pmr(false, info.timeout_us)?;
+        Ok(PmrRegion::new(bar_va, size, self.bar, info.wbm))
+    }
+
+    // the region is made persistent before the pmr is disabled
+    pub fn disable_pmr(&mut self, region: PmrRegion) -> NvmeResult<()> {
+        let info = self.pmr_info().ok_or(NvmeError::NotSupported)?;
+        // NotSupported: the controller has no barrier, nothing more can be done
+        let _ = region.persist();
+
+        unsafe { write_volatile((self.bar + NVME_REG_PMRCTL) as *mut u32, 0) }
+        self.nvme_wait_pmr(true, info.timeout_us)
+    }
+
+    // poll PMRSTS.NRDY, a non-zero error field fails the wait
+    fn nvme_wait_pmr(&self, not_ready: bool, timeout_us: u64) -> NvmeResult<()> {
+        let timeout_us = timeout_us.max(NVME_PMR_MIN_TIMEOUT_US);
+        let start = T::current_time_us();
+        loop {
+            let pmrsts = unsafe { read_volatile((self.bar + NVME_REG_PMRSTS) as *const u32) };
+            if pmrsts & 0xff != 0 {
+                return Err(NvmeError::PmrFailed { err: pmrsts as u8 });
+            }
+            if (pmrsts & NVME_PMRSTS_NRDY != 0) == not_ready {
+                return Ok(());
+            }
+            if T::current_time_us() - start > timeout_us {
+                return Err(NvmeError::Timeout);
+            }
+        }
+    }
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    pub fn boot_partition_info(&self) -> NvmeResult<NvmeBootPartitionInfo> {
+        if self.nvme_read_cap() & NVME_CAP_BPS == 0 {
+            return Err(NvmeError::NotSupported);
+        }
+        let bpinfo = unsafe { read_volatile((self.bar + NVME_REG_BPINFO) as *const u32) };
+        Ok(NvmeBootPartitionInfo::from_reg(bpinfo))
+    }
+
+    // register based read, see read_boot_partition_bar
+    pub fn read_boot_partition(&self, id: u8, offset: usize, buf: &mut [u8]) -> NvmeResult<()> {
+        Self::read_boot_partition_bar(self.bar, id, offset, buf)
+    }
+
+    // read boot partition id through BPRSEL / BPMBL / BPINFO only, no queue is used so
+    // this works whether the controller is enabled or not, e.g. from a bootloader
+    // 1. point BPMBL at a dma bounce buffer
+    // 2. write size, offset and id to BPRSEL to start the read
+    // 3. poll BPINFO.BRS until the read completes
+    pub fn read_boot_partition_bar(
+        bar: usize,
+        id: u8,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> NvmeResult<()> {
+        let cap_low = unsafe { read_volatile((bar + NVME_REG_CAP) as *const u32) };
+        let cap_high = unsafe { read_volatile((bar + NVME_REG_CAP + 4) as *const u32) };
+        if ((cap_high as u64) << 32 | cap_low as u64) & NVME_CAP_BPS == 0 {
+            return Err(NvmeError::NotSupported);
+        }
+        let bpinfo = unsafe { read_volatile((bar + NVME_REG_BPINFO) as *const u32) };
+        let info = NvmeBootPartitionInfo::from_reg(bpinfo);
+        if id > 1 || offset.checked_add(buf.len()).map_or(true, |end| end > info.size) {
+            return Err(NvmeError::InvalidArgument);
+        }
+
+        let chunk_size = NVME_BP_READ_CHUNK * NVME_BP_READ_UNIT;
+        let bounce_va = D::dma_alloc(chunk_size);
+        let bounce_pa = D::virt_to_phys(bounce_va);
+        // BPMBL holds a 4KiB aligned address only
+        if bounce_pa & 0xfff != 0 {
+            D::dma_dealloc(bounce_va, chunk_size);
+            return Err(NvmeError::InvalidArgument);
+        }
+        let bounce = unsafe { slice::from_raw_parts(bounce_va as *const u8, chunk_size) };
+        unsafe { write_volatile((bar + NVME_REG_BPMBL) as *mut u64, bounce_pa as u64) }
+
+        let mut result = Ok(());
+        let mut done = 0;
+        while done < buf.len() {
+            // reads start on a 4KiB boundary
+            let pos = offset + done;
+            let skip = pos % NVME_BP_READ_UNIT;
+            let len = (buf.len() - done).min(chunk_size - skip);
+            let units = (skip + len + NVME_BP_READ_UNIT - 1) / NVME_BP_READ_UNIT;
+
+            let bprsel = nvme_bprsel(id, pos, units);
+            let bpinfo = unsafe { read_volatile((bar + NVME_REG_BPINFO) as *const u32) };
+            let stale = NvmeBootPartitionInfo::from_reg(bpinfo).brs;
+            unsafe { write_volatile((bar + NVME_REG_BPRSEL) as *mut u32, bprsel) }
+
+            result = Self::nvme_wait_bp_read(bar, stale);
+            if result.is_err() {
+                break;
+            }
+            buf[done..done + len].copy_from_slice(&bounce[skip..skip + len]);
+            done += len;
+        }
+
+        D::dma_dealloc(bounce_va, chunk_size);
+        result
+    }
+
+    // stale is BRS before the read was started. a success or error left by the previous
+    // read only counts once BRS has moved on to reading
+    fn nvme_wait_bp_read(bar: usize, stale: u8) -> NvmeResult<()> {
+        let start = T::current_time_us();
+        let mut started = stale == NVME_BPINFO_BRS_NONE || stale == NVME_BPINFO_BRS_READING;
+        loop {
+            let bpinfo = unsafe { read_volatile((bar + NVME_REG_BPINFO) as *const u32) };
+            match NvmeBootPartitionInfo::from_reg(bpinfo).brs {
+                NVME_BPINFO_BRS_READING => started = true,
+                NVME_BPINFO_BRS_SUCCESS if started => return Ok(()),
+                NVME_BPINFO_BRS_ERROR if started => {
+                    return Err(NvmeError::BootPartitionReadFailed)
+                }
+                _ => {}
+            }
+            if T::current_time_us() - start > NVME_BP_READ_TIMEOUT_US {
+                return Err(NvmeError::Timeout);
+            }
+        }
+    }
+
+    // download the image and replace boot partition id with it, the active boot
+    // partition does not change
+    pub fn write_boot_partition(&mut self, id: u8, image: &[u8]) -> NvmeResult<()> {
+        let info = self.boot_partition_info()?;
+        if id > 1 || image.len() > info.size {
+            return Err(NvmeError::InvalidArgument);
+        }
+
+        self.firmware_download(0, image)?;
+        self.nvme_bp_commit(id, NvmeCommitAction::ReplaceBootPartition)
+    }
+
+    // make boot partition id the one read at boot
+    pub fn activate_boot_partition(&mut self, id: u8) -> NvmeResult<()> {
+        self.boot_partition_info()?;
+        if id > 1 {
+            return Err(NvmeError::InvalidArgument);
+        }
+        self.nvme_bp_commit(id, NvmeCommitAction::ActivateBootPartition)
+    }
+
+    // firmware commit with the boot partition id in bit 31
+    fn nvme_bp_commit(&mut self, id: u8, action: NvmeCommitAction) -> NvmeResult<()> {
+        let mut cmd = NvmeCommonCommand::new();
+        cmd.opcode = NVME_ADMIN_FW_COMMIT;
+        cmd.cdw10 = (action as u32) << 3 | (id as u32) << 31;
+        let cqe = self.submit_sync_command(cmd)?;
+        NvmeError::from_status(cqe.status)
+    }
+}
+
+// BPRSEL reading units of 4KiB from pos of boot partition id, pos is 4KiB aligned down
+fn nvme_bprsel(id: u8, pos: usize, units: usize) -> u32 {
+    units as u32 | ((pos / NVME_BP_READ_UNIT) as u32) << 10 | (id as u32) << 31
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // shadow doorbells for io queues: doorbells are written to host memory and the mmio
+    // doorbell only when the controller asks for it, mostly useful on emulated controllers
+    // the admin queue keeps mmio doorbells, like linux
+    pub fn enable_shadow_doorbells(&mut self) -> NvmeResult<()> {
+        if self.ctrl_info()?.oacs & NVME_CTRL_OACS_DBBUF == 0 {
+            return Err(NvmeError::NotSupported);
+        }
+        if self.dbbuf.is_some() {
+            return Ok(());
+        }
+
+        let db_va = D::dma_alloc(PAGE_SIZE);
+        let ei_va = D::dma_alloc(PAGE_SIZE);
+        self.dbbuf = Some((db_va, ei_va));
+        if let Err(err) = self.nvme_dbbuf_set() {
+            self.dbbuf = None;
+            D::dma_dealloc(db_va, PAGE_SIZE);
+            D::dma_dealloc(ei_va, PAGE_SIZE);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // seed the shadow doorbells with the current queue positions, then hand both pages to
+    // the controller. io queues stay locked so no doorbell is written in between
+    fn nvme_dbbuf_set(&self) -> NvmeResult<()> {
+        let (db_va, ei_va) = match self.dbbuf {
+            Some(dbbuf) => dbbuf,
+            None => return Ok(()),
+        };
+
+        let mut io_queues: Vec<MutexGuard<NvmeQueue<D>>> =
+            self.io_queues.iter().map(|q| q.lock()).collect();
+        unsafe {
+            core::ptr::write_bytes(db_va as *mut u8, 0, PAGE_SIZE);
+            core::ptr::write_bytes(ei_va as *mut u8, 0, PAGE_SIZE);
+        }
+        for nvmeq in io_queues.iter_mut() {
+            nvmeq.dbbuf_db = 0;
+            nvmeq.dbbuf_ei = 0;
+            let db = db_va + nvmeq.db_offset;
+            unsafe {
+                write_volatile(db as *mut u32, nvmeq.sq_tail as u32);
+                write_volatile((db + 0x4) as *mut u32, nvmeq.cq_head as u32);
+            }
+        }
+
+        let mut cmd = NvmeCommonCommand::new();
+        cmd.opcode = NVME_ADMIN_DBBUF;
+        cmd.prp1 = D::virt_to_phys(db_va) as u64;
+        cmd.prp2 = D::virt_to_phys(ei_va) as u64;
+        self.nvme_admin_command(cmd)?;
+
+        for nvmeq in io_queues.iter_mut() {
+            nvmeq.dbbuf_db = db_va + nvmeq.db_offset;
+            nvmeq.dbbuf_ei = ei_va + nvmeq.db_offset;
+        }
+        Ok(())
+    }
+}
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // read blocks from slba of a namespace formatted with metadata. buf holds the lba
+    // data, with the metadata of each lba appended on extended lba formats. meta is the
+    // separate metadata buffer otherwise. protection information is verified on the host
+    // unless the controller strips it
+    pub fn read_pi(
+        &mut self,
+        nsid: u32,
+        slba: u64,
+        buf: &mut [u8],
+        mut meta: Option<&mut [u8]>,
+        prot: NvmeProtection,
+    ) -> NvmeResult<()> {
+        let id_ns = self.ns_info(nsid)?;
+        let nlb = nvme_pi_blocks(&id_ns, buf.len(), meta.as_deref().map(|m| m.len()), &prot)?;
+        let cmd = nvme_pi_command(NVME_CMD_READ, &id_ns, nsid, slba, nlb, &prot);
+        self.nvme_rw_meta(cmd, buf, meta.as_deref_mut())?;
+
+        if !nvme_pi_on_host(&id_ns, &prot) {
+            return Ok(());
+        }
+        nvme_pi_verify(&id_ns, slba, buf, meta, &prot, nlb)
+    }
+
+    // write blocks to slba of a namespace formatted with metadata, buffers are laid out
+    // as for read_pi. the host generates the protection information into the metadata
+    // unless the controller inserts it
+    pub fn write_pi(
+        &mut self,
+        nsid: u32,
+        slba: u64,
+        buf: &mut [u8],
+        mut meta: Option<&mut [u8]>,
+        prot: NvmeProtection,
+    ) -> NvmeResult<()> {
+        let id_ns = self.ns_info(nsid)?;
+        let nlb = nvme_pi_blocks(&id_ns, buf.len(), meta.as_deref().map(|m| m.len()), &prot)?;
+
+        if nvme_pi_on_host(&id_ns, &prot) {
+            nvme_pi_generate(&id_ns, slba, buf, meta.as_deref_mut(), &prot, nlb);
+        }
+
+        let cmd = nvme_pi_command(NVME_CMD_WRITE, &id_ns, nsid, slba, nlb, &prot);
+        self.nvme_rw_meta(cmd, buf, meta)
+    }
+
+    // the separate metadata buffer is bounced through dma memory, mptr must be contiguous
+    fn nvme_rw_meta(
+        &self,
+        mut cmd: NvmeRWCommand,
+        buf: &mut [u8],
+        meta: Option<&mut [u8]>,
+    ) -> NvmeResult<()> {
+        self.nvme_recover_pending()?;
+        let opcode = cmd.opcode;
+        let meta_len = meta.as_ref().map_or(0, |m| m.len());
+        // the bounce buffer only exists with metadata, never build a slice over address 0
+        let mut bounce = None;
+        if let Some(meta) = meta.as_ref() {
+            let meta_va = D::dma_alloc(meta_len);
+            let dma = unsafe { slice::from_raw_parts_mut(meta_va as *mut u8, meta_len) };
+            if opcode == NVME_CMD_WRITE {
+                dma.copy_from_slice(meta);
+            }
+            cmd.metadata = D::virt_to_phys(meta_va) as u64;
+            bounce = Some(dma);
+        }
+
+        let common_cmd = unsafe { core::mem::transmute(cmd) };
+        let buf_va = buf.as_ptr() as usize;
+        let result = self
+            .submit_io_command_buf(common_cmd, buf_va, buf.len(), NvmeQueuePriority::Medium)
+            .and_then(|cqe| NvmeError::from_status(cqe.status));
+
+        if let (Some(meta), Some(dma)) = (meta, bounce) {
+            if result.is_ok() && opcode == NVME_CMD_READ {
+                meta.copy_from_slice(dma);
+            }
+            D::dma_dealloc(dma.as_ptr() as usize, meta_len);
+        }
+        result
+    }
+}
+
+// number of blocks described by the buffers, checked against the namespace format
+fn nvme_pi_blocks(
+    id_ns: &NvmeIdNs,
+    buf_len: usize,
+    meta_len: Option<usize>,
+    prot: &NvmeProtection,
+) -> NvmeResult<usize> {
+    let pi_type = id_ns.pi_type();
+    if pi_type == NvmePiType::None && (prot.pract || prot.checks != 0) {
+        return Err(NvmeError::NotSupported);
+    }
+
+    let lba_size = id_ns.lba_size();
+    let ms = id_ns.metadata_size();
+    // with pract and only the protection information as metadata nothing is transferred
+    let stripped = pi_type != NvmePiType::None && prot.pract && ms == NvmePi::SIZE;
+    let separate = ms != 0 && !stripped && !id_ns.extended_lba();
+    let block_size = if ms == 0 || stripped || separate { lba_size } else { lba_size + ms };
+
+    let nlb = buf_len / block_size;
+    if nlb == 0 || nlb > 0x10000 || buf_len % block_size != 0 {
+        return Err(NvmeError::InvalidArgument);
+    }
+    match meta_len {
+        Some(len) if !separate || len != nlb * ms => Err(NvmeError::InvalidArgument),
+        None if separate => Err(NvmeError::InvalidArgument),
+        _ => Ok(nlb),
+    }
+}
+
+// whether the host generates and verifies the protection information
+fn nvme_pi_on_host(id_ns: &NvmeIdNs, prot: &NvmeProtection) -> bool {
+    id_ns.pi_type() != NvmePiType::None
+        && !(prot.pract && id_ns.metadata_size() == NvmePi::SIZE)
+}
+
+// lba data and metadata of block i
+fn nvme_pi_block<'a>(
+    id_ns: &NvmeIdNs,
+    buf: &'a mut [u8],
+    meta: Option<&'a mut [u8]>,
+    i: usize,
+) -> (&'a mut [u8], &'a mut [u8]) {
+    let lba_size = id_ns.lba_size();
+    let ms = id_ns.metadata_size();
+    match meta {
+        Some(meta) => (
+            &mut buf[i * lba_size..(i + 1) * lba_size],
+            &mut meta[i * ms..(i + 1) * ms],
+        ),
+        None => buf[i * (lba_size + ms)..(i + 1) * (lba_size + ms)].split_at_mut(lba_size),
+    }
+}
+
+// fill in the protection information of nlb blocks
+fn nvme_pi_generate(
+    id_ns: &NvmeIdNs,
+    slba: u64,
+    buf: &mut [u8],
+    mut meta: Option<&mut [u8]>,
+    prot: &NvmeProtection,
+    nlb: usize,
+) {
+    let pi_type = id_ns.pi_type();
+    for i in 0..nlb {
+        let (data, md) = nvme_pi_block(id_ns, buf, meta.as_deref_mut(), i);
+        let pi_offset = if id_ns.pi_first() { 0 } else { md.len() - NvmePi::SIZE };
+        let pi = NvmePi {
+            guard: nvme_pi_guard(data, md, id_ns.pi_first()),
+            apptag: prot.apptag,
+            reftag: nvme_pi_reftag(pi_type, slba, prot, i),
+        };
+        pi.write_to(&mut md[pi_offset..]);
+    }
+}
+
+// check the protection information of nlb blocks, escape tags disable the checks
+fn nvme_pi_verify(
+    id_ns: &NvmeIdNs,
+    slba: u64,
+    buf: &mut [u8],
+    mut meta: Option<&mut [u8]>,
+    prot: &NvmeProtection,
+    nlb: usize,
+) -> NvmeResult<()> {
+    let pi_type = id_ns.pi_type();
+    for i in 0..nlb {
+        let (data, md) = nvme_pi_block(id_ns, buf, meta.as_deref_mut(), i);
+        let pi_offset = if id_ns.pi_first() { 0 } else { md.len() - NvmePi::SIZE };
+        let pi = NvmePi::parse(&md[pi_offset..]);
+        if pi.apptag == NVME_PI_APPTAG_ESCAPE
+            && (pi_type != NvmePiType::Type3 || pi.reftag == NVME_PI_REFTAG_ESCAPE)
+        {
+            continue;
+        }
+
+        let guard_ok = prot.checks & NVME_RW_PRINFO_PRCHK_GUARD == 0
+            || pi.guard == nvme_pi_guard(data, md, id_ns.pi_first());
+        let app_ok = prot.checks & NVME_RW_PRINFO_PRCHK_APP == 0
+            || (pi.apptag ^ prot.apptag) & prot.appmask == 0;
+        let ref_ok = prot.checks & NVME_RW_PRINFO_PRCHK_REF == 0
+            || pi_type == NvmePiType::Type3
+            || pi.reftag == nvme_pi_reftag(pi_type, slba, prot, i);
+        if !(guard_ok && app_ok && ref_ok) {
+            return Err(NvmeError::PiCheckFailed { lba: slba + i as u64 });
+        }
+    }
+    Ok(())
+}
+
+// the guard covers the lba data, and the metadata ahead of trailing protection information
+fn nvme_pi_guard(data: &[u8], md: &[u8], pi_first: bool) -> u16 {
+    let crc = nvme_crc16_t10dif(0, data);
+    if pi_first {
+        crc
+    } else {
+        nvme_crc16_t10dif(crc, &md[..md.len() - NvmePi::SIZE])
+    }
+}
+
+fn nvme_pi_reftag(pi_type: NvmePiType, slba: u64, prot: &NvmeProtection, i: usize) -> u32 {
+    match pi_type {
+        NvmePiType::Type1 => (slba + i as u64) as u32,
+        NvmePiType::Type2 => prot.reftag.wrapping_add(i as u32),
+        _ => prot.reftag,
+    }
+}
+
+fn nvme_pi_command(
+    opcode: u8,
+    id_ns: &NvmeIdNs,
+    nsid: u32,
+    slba: u64,
+    nlb: usize,
+    prot: &NvmeProtection,
+) -> NvmeRWCommand {
+    let mut cmd = NvmeRWCommand::new_read_command();
+    cmd.opcode = opcode;
+    cmd.nsid = nsid;
+    cmd.slba = slba;
+    cmd.length = (nlb - 1) as u16;
+    cmd.control = prot.checks;
+    if prot.pract {
+        cmd.control |= NVME_RW_PRINFO_PRACT;
+    }
+    cmd.reftag = nvme_pi_reftag(id_ns.pi_type(), slba, prot, 0);
+    cmd.apptag = prot.apptag;
+    cmd.appmask = prot.appmask;
+    cmd
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // identify controller of the zoned namespace command set
+    pub fn zns_ctrl_info(&mut self) -> NvmeResult<NvmeZnsIdCtrl> {
+        let data = self.identify_csi(0, NVME_ID_CNS_CS_CTRL, NVME_CSI_ZNS)?;
+        Ok(NvmeZnsIdCtrl::parse(&data))
+    }
+
+    // identify namespace of the zoned namespace command set, fails on other namespaces
+    pub fn zns_ns_info(&mut self, nsid: u32) -> NvmeResult<NvmeZnsIdNs> {
+        let data = self.identify_csi(nsid, NVME_ID_CNS_CS_NS, NVME_CSI_ZNS)?;
+        Ok(NvmeZnsIdNs::parse(&data))
+    }
+
+    // open, close, finish, reset or offline the zone starting at slba, or every zone
+    // the action applies to when all is set
+    pub fn zone_mgmt_send(
+        &self,
+        nsid: u32,
+        slba: u64,
+        action: NvmeZoneAction,
+        all: bool,
+    ) -> NvmeResult<()> {
+        let mut cmd = NvmeCommonCommand::new();
+        cmd.opcode = NVME_CMD_ZONE_MGMT_SEND;
+        cmd.nsid = nsid;
+        cmd.cdw10 = slba as u32;
+        cmd.cdw11 = (slba >> 32) as u32;
+        cmd.cdw13 = action as u32;
+        if all {
+            cmd.cdw13 |= NVME_ZONE_SEND_SELECT_ALL;
+        }
+        let cqe = self.submit_io_command(cmd)?;
+        NvmeError::from_status(cqe.status)
+    }
+
+    // descriptors of at most max_zones zones matching filter, from the zone containing slba.
+    // the report is read through the io data buffer, one partial report at a time
+    pub fn report_zones(
+        &mut self,
+        nsid: u32,
+        slba: u64,
+        filter: NvmeZoneFilter,
+        max_zones: usize,
+    ) -> NvmeResult<Vec<NvmeZoneDescriptor>> {
+        let id_ns = self.ns_info(nsid)?;
+        let zone_size = self.zns_ns_info(nsid)?.zone_size(&id_ns);
+        if zone_size == 0 {
+            return Err(NvmeError::NotSupported);
+        }
+
+        // prp1 + prp2 without a prp list
+        let len = PAGE_SIZE * 2;
+        let per_report = (len - NVME_ZONE_REPORT_HDR_SIZE) / NVME_ZONE_DESC_SIZE;
+        // held until the last report has been parsed
+        let buf = self.io_buf.lock();
+        let data_pa = buf.pa;
+
+        let mut zones = Vec::new();
+        let mut slba = slba;
+        while zones.len() < max_zones && slba < id_ns.nsze {
+            let mut cmd = NvmeCommonCommand::new();
+            cmd.opcode = NVME_CMD_ZONE_MGMT_RECV;
+            cmd.nsid = nsid;
+            cmd.prp1 = data_pa as u64;
+            cmd.prp2 = (data_pa + PAGE_SIZE) as u64;
+            cmd.cdw10 = slba as u32;
+            cmd.cdw11 = (slba >> 32) as u32;
+            cmd.cdw12 = (len / 4 - 1) as u32;
+            cmd.cdw13 = NVME_ZONE_RECV_REPORT | (filter as u32) << 8 | NVME_ZONE_RECV_PARTIAL;
+            let cqe = self.submit_io_command(cmd)?;
+            NvmeError::from_status(cqe.status)?;
+
+            // with a partial report the count is of the descriptors returned
+            let data = buf.as_slice();
+            let nr = (le64(data, 0) as usize).min(per_report);
+            for i in 0..nr.min(max_zones - zones.len()) {
+                let offset = NVME_ZONE_REPORT_HDR_SIZE + i * NVME_ZONE_DESC_SIZE;
+                zones.push(NvmeZoneDescriptor::parse(&data[offset..]));
+            }
+            if nr < per_report {
+                break;
+            }
+            slba = zones[zones.len() - 1].zslba + zone_size;
+        }
+        Ok(zones)
+    }
+
+    // append buf to the zone starting at zslba, return the lba the data was written at
+    pub fn zone_append(&mut self, nsid: u32, zslba: u64, buf: &[u8]) -> NvmeResult<u64> {
+        let lba_size = self.ns_info(nsid)?.lba_size();
+        let mut max = self.max_transfer_size()?;
+        let zasl = self.zns_ctrl_info()?.zasl;
+        if zasl != 0 {
+            max = max.min(nvme_mpsmin_units(zasl, self.nvme_read_cap()));
+        }
+        if buf.is_empty() || buf.len() % lba_size != 0 || buf.len() > max {
+            return Err(NvmeError::InvalidArgument);
+        }
+
+        let mut cmd = NvmeRWCommand::new_write_command();
+        cmd.opcode = NVME_CMD_ZONE_APPEND;
+        cmd.nsid = nsid;
+        cmd.slba = zslba;
+        cmd.length = (buf.len() / lba_size - 1) as u16;
+
+        let common_cmd = unsafe { core::mem::transmute(cmd) };
+        let buf_va = buf.as_ptr() as usize;
+        let prio = NvmeQueuePriority::Medium;
+        let cqe = self.submit_io_command_buf(common_cmd, buf_va, buf.len(), prio)?;
+        NvmeError::from_status(cqe.status)?;
+        Ok(cqe.result)
+    }
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // command sets the controller can be enabled with
+    pub fn css_cap(&self) -> NvmeCssCap {
+        NvmeCssCap::from_cap(self.nvme_read_cap())
+    }
+
+    // CC.CSS selects all supported i/o command sets, chosen at enable when CAP.CSS allows
+    pub fn io_command_sets_enabled(&self) -> bool {
+        let cc = unsafe { read_volatile((self.bar + NVME_REG_CC) as *const u32) };
+        cc & NVME_CC_CSS_MASK == NVME_CC_CSS_CSI
+    }
+
+    // i/o command set combinations the controller supports
+    pub fn iocs_info(&mut self) -> NvmeResult<NvmeIdIocs> {
+        if !self.io_command_sets_enabled() {
+            return Err(NvmeError::NotSupported);
+        }
+        let cntlid = self.ctrl_info()?.cntlid;
+        let data = self.nvme_identify(0, NVME_ID_CNS_IOCS, NVME_CSI_NVM, cntlid)?;
+        Ok(NvmeIdIocs::parse(&data))
+    }
+
+    // index of the command set vector in use
+    pub fn get_iocs_profile(&mut self, sel: NvmeFeatureSel) -> NvmeResult<u16> {
+        Ok((self.get_features(NVME_FEAT_IOCS_PROFILE, sel)? & 0x1ff) as u16)
+    }
+
+    // the controller changes the attached namespaces of command sets left out of the profile,
+    // call rescan_namespaces afterwards
+    pub fn set_iocs_profile(&mut self, index: u16, save: bool) -> NvmeResult<()> {
+        if !self.io_command_sets_enabled() || index as usize >= NvmeIdIocs::MAX_VECTORS {
+            return Err(NvmeError::InvalidArgument);
+        }
+        self.nvme_set_features(NVME_FEAT_IOCS_PROFILE, 0, index as u32, save, None)?;
+        Ok(())
+    }
+
+    // select the first profile including every command set in csis, return its index
+    pub fn enable_command_sets(&mut self, csis: &[u8]) -> NvmeResult<u16> {
+        let index = self.iocs_info()?.find(csis).ok_or(NvmeError::NotSupported)?;
+        if self.get_iocs_profile(NvmeFeatureSel::Current)? != index {
+            self.set_iocs_profile(index, false)?;
+        }
+        Ok(index)
+    }
+
+    // namespace identifiers and command set of a namespace
+    pub fn ns_ids(&mut self, nsid: u32) -> NvmeResult<NvmeNsIds> {
+        let data = self.identify(nsid, NVME_ID_CNS_NS_DESC_LIST)?;
+        Ok(NvmeNsIds::parse(&data))
+    }
+
+    pub fn ns_command_set(&mut self, nsid: u32) -> NvmeResult<u8> {
+        Ok(self.ns_ids(nsid)?.csi)
+    }
+}
+
+impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+    // identify namespace of the key value command set, fails on other namespaces
+    pub fn kv_ns_info(&mut self, nsid: u32) -> NvmeResult<NvmeKvIdNs> {
+        let data = self.identify_csi(nsid, NVME_ID_CNS_CS_NS, NVME_CSI_KV)?;
+        Ok(NvmeKvIdNs::parse(&data))
+    }
+
+    // handle of a namespace whose command set is key value
+    pub fn kv_namespace(&mut self, nsid: u32) -> NvmeResult<KvNamespace<'_, D, I, T>> {
+        if self.ns_command_set(nsid)? != NVME_CSI_KV {
+            return Err(NvmeError::NotSupported);
+        }
+        let info = self.kv_ns_info(nsid)?;
+        let format = info.format_in_use();
+        let max_transfer = self.max_transfer_size()?;
+        Ok(KvNamespace::new(self, nsid, info, format, max_transfer))
+    }
+}
+
+// // async read/write
+// use core::{
+//     future::Future,
+//     pin::Pin,
+//     task::{Context, Poll},
+// };
+// use core::sync::atomic::{AtomicBool, Ordering, AtomicUsize};
+
+// impl<D: DmaAllocator, I: IrqController, T: Timer> NvmeInterface<D, I, T> {
+//     fn async_read_block(&self, block_id: usize, read_buf: &mut [u8]) -> NvmeFuture{
+//         let cid = NVME_COMMAND_ID.lock().load(Ordering::SeqCst);
+//         NVME_COMMAND_ID.lock().store(cid + 1, Ordering::Relaxed);
+
+//         self.send_read_command(block_id, read_buf, cid);
+//         let f =  NvmeFuture::new(cid);
+//         f
+//     }
+
+//     async fn async_write_block(&self, block_id: usize, write_buf: &[u8]) -> NvmeFuture{
+//         let cid = NVME_COMMAND_ID.lock().load(Ordering::SeqCst);
+//         NVME_COMMAND_ID.lock().store(cid + 1, Ordering::Relaxed);
+
+//         self.send_write_command(block_id, write_buf, cid);
+//         let f =  NvmeFuture::new(cid);
+//         f
+//     }
+// }
+
+// lazy_static::lazy_static! {
+//     pub static ref NVME_MAP: Mutex<BTreeMap<usize, (Waker, Arc<AtomicBool>)>> = Mutex::new(BTreeMap::new());
+// }
+
+
+// lazy_static::lazy_static! {
+//     pub static ref NVME_COMMAND_ID: Mutex<AtomicUsize> = Mutex::new(AtomicUsize::new(0));
+// }
+
+
+// pub struct NvmeFuture{
+//     command_id: usize,
+//     irq_occurred: Arc<AtomicBool>,
+    
+// }
+
+// impl NvmeFuture{
+//     fn new(id: usize)-> Self{
+
+//         Self {
+//             command_id: id,
+//             irq_occurred: Arc::new(AtomicBool::new(false))
+//         }
+//     }
+// }
+
+// impl Future for NvmeFuture{
+
+//     type Output = ();
+
+//     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+//         let waker = cx.waker().clone();
+//         if !self.irq_occurred.load(Ordering::SeqCst) {
+//             NVME_MAP.lock().insert(self.command_id, (waker, self.irq_occurred.clone()),);
+//         }else{
+//             return Poll::Ready(());
+//         }
+//         Poll::Pending
+//     }
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn prp_pages_within_first_page() {
+        assert_eq!(nvme_prp_pages(0x1000, PAGE_SIZE), Ok(1));
+        assert_eq!(nvme_prp_pages(0x1004, PAGE_SIZE - 4), Ok(1));
+        assert_eq!(nvme_prp_pages(0x1ffc, 4), Ok(1));
+    }
+
+    #[test]
+    fn prp_pages_crossing_pages() {
+        assert_eq!(nvme_prp_pages(0x1004, PAGE_SIZE - 3), Ok(2));
+        assert_eq!(nvme_prp_pages(0x1ffc, 8), Ok(2));
+        assert_eq!(nvme_prp_pages(0x1000, 2 * PAGE_SIZE), Ok(2));
+        // a third page needs the prp list
+        assert_eq!(nvme_prp_pages(0x1000, 2 * PAGE_SIZE + 4), Ok(3));
+        assert_eq!(nvme_prp_pages(0x1800, 2 * PAGE_SIZE), Ok(3));
+    }
+
+    #[test]
+    fn prp_pages_list_limit() {
+        let max = (NVME_PRP_LIST_ENTRIES + 1) * PAGE_SIZE;
+        assert_eq!(nvme_prp_pages(0x1000, max), Ok(NVME_PRP_LIST_ENTRIES + 1));
+        assert_eq!(nvme_prp_pages(0x1000, max + 4), Err(NvmeError::InvalidArgument));
+        // the offset into the first page counts
+        assert_eq!(nvme_prp_pages(0x1004, max), Err(NvmeError::InvalidArgument));
+    }
+
+    #[test]
+    fn prp_pages_invalid_buffer() {
+        assert_eq!(nvme_prp_pages(0x1002, 8), Err(NvmeError::InvalidArgument));
+        assert_eq!(nvme_prp_pages(0x1000, 0), Err(NvmeError::InvalidArgument));
+    }
+
+    #[test]
+    fn max_transfer_mdts() {
+        let prp_list_max = NVME_PRP_LIST_ENTRIES * PAGE_SIZE;
+        assert_eq!(nvme_max_transfer(0, 0), prp_list_max);
+        // 2^5 pages of CAP.MPSMIN
+        assert_eq!(nvme_max_transfer(5, 0), 128 * 1024);
+        assert_eq!(nvme_max_transfer(5, 1 << 48), 256 * 1024);
+        assert_eq!(nvme_max_transfer(10, 0), prp_list_max);
+        // exponents past the width of usize do not overflow
+        assert_eq!(nvme_max_transfer(60, 0xf << 48), prp_list_max);
+        assert_eq!(nvme_max_transfer(255, 0xf << 48), prp_list_max);
+    }
+
+    #[test]
+    fn mpsmin_units_saturate() {
+        assert_eq!(nvme_mpsmin_units(1, 0), 8 * 1024);
+        assert_eq!(nvme_mpsmin_units(3, 2 << 48), 128 * 1024);
+        assert_eq!(nvme_mpsmin_units(52, 0), usize::MAX);
+        assert_eq!(nvme_mpsmin_units(255, 0xf << 48), usize::MAX);
+    }
+
+    #[test]
+    fn shutdown_timeout_clamped() {
+        assert_eq!(nvme_shutdown_timeout(0), NVME_SHUTDOWN_TIMEOUT_US);
+        assert_eq!(nvme_shutdown_timeout(8_000_000), 8_000_000);
+        assert_eq!(nvme_shutdown_timeout(u32::MAX), NVME_SHUTDOWN_TIMEOUT_MAX_US);
+    }
+
+    #[test]
+    fn csts_fatal() {
+        assert!(!nvme_csts_fatal(0));
+        assert!(!nvme_csts_fatal(NVME_CSTS_RDY));
+        assert!(nvme_csts_fatal(NVME_CSTS_RDY | NVME_CSTS_CFS));
+        assert!(nvme_csts_fatal(u32::MAX));
+    }
+
+    #[test]
+    fn aborted_status() {
+        let cqe = |sct: u8, sc: u8| NvmeCompletion {
+            status: (sct as u16) << 9 | (sc as u16) << 1,
+            ..Default::default()
+        };
+        assert!(nvme_aborted(&cqe(NVME_SCT_GENERIC, NVME_SC_ABORT_REQ)));
+        assert!(!nvme_aborted(&cqe(NVME_SCT_GENERIC, NVME_SC_SUCCESS)));
+        assert!(!nvme_aborted(&cqe(NVME_SCT_MEDIA, NVME_SC_ABORT_REQ)));
+    }
+
+    #[test]
+    fn queue_count_decode() {
+        // 8 submission queues, 4 completion queues
+        assert_eq!(nvme_queue_count(0x0003_0007), 4);
+        assert_eq!(nvme_queue_count(0), 1);
+        assert_eq!(nvme_queue_count(u32::MAX), u16::MAX);
+    }
+
+    #[test]
+    fn bprsel_fields() {
+        assert_eq!(nvme_bprsel(0, 0, 1), 1);
+        // 8 units from 12KiB into boot partition 1, the offset is in 4KiB units
+        assert_eq!(nvme_bprsel(1, 0x3000 + 0x10, 8), 1 << 31 | 3 << 10 | 8);
+        assert_eq!(nvme_bprsel(0, 0, NVME_BP_READ_MAX_UNITS), 0x3ff);
+    }
+
+    #[test]
+    fn cap_timeout() {
+        assert_eq!(nvme_cap_timeout_us(20 << 24), 10 * 1000 * 1000);
+        // a zero timeout still waits one unit
+        assert_eq!(nvme_cap_timeout_us(0), 500 * 1000);
+        assert_eq!(nvme_cap_timeout_us(u64::MAX), 0xff * 500 * 1000);
+    }
+
+    // update a local shadow doorbell holding old against eventidx, return whether the
+    // mmio doorbell is needed and the new shadow doorbell
+    fn dbbuf_update(old: u32, event_idx: u32, value: u16) -> (bool, u32) {
+        let mut db = old;
+        let ei = event_idx;
+        let db_va = &mut db as *mut u32 as usize;
+        let ei_va = &ei as *const u32 as usize;
+        let need = nvme_dbbuf_update(db_va, ei_va, value);
+        (need, db)
+    }
+
+    #[test]
+    fn dbbuf_update_writes_shadow() {
+        assert_eq!(dbbuf_update(3, 0, 5).1, 5);
+    }
+
+    #[test]
+    fn dbbuf_update_eventidx() {
+        // eventidx in [old, value) was passed
+        assert!(dbbuf_update(3, 3, 5).0);
+        assert!(dbbuf_update(3, 4, 5).0);
+        // eventidx not reached yet, or already behind old
+        assert!(!dbbuf_update(3, 5, 5).0);
+        assert!(!dbbuf_update(3, 9, 5).0);
+        assert!(!dbbuf_update(3, 2, 5).0);
+    }
+
+    #[test]
+    fn dbbuf_update_eventidx_wraps() {
+        assert!(dbbuf_update(0xfffe, 0xffff, 1).0);
+        assert!(dbbuf_update(0xfffe, 0, 1).0);
+        assert!(!dbbuf_update(0xfffe, 1, 1).0);
+        assert!(!dbbuf_update(0xfffe, 0xfffd, 1).0);
+    }
+
+    // 512 byte lbas with 8 bytes of metadata
+    fn pi_ns(dps: u8, extended: bool) -> NvmeIdNs {
+        let mut id_ns = NvmeIdNs { dps, ..Default::default() };
+        id_ns.lbaf[0] = NvmeLbaFormat { ms: 8, lbads: 9, rp: 0 };
+        if extended {
+            id_ns.flbas |= NVME_NS_FLBAS_META_EXT;
+        }
+        id_ns
+    }
+
+    fn pi_prot(checks: u16) -> NvmeProtection {
+        NvmeProtection { checks, apptag: 0x1234, appmask: 0xffff, reftag: 7, ..Default::default() }
+    }
+
+    const PI_CHECKS: u16 =
+        NVME_RW_PRINFO_PRCHK_GUARD | NVME_RW_PRINFO_PRCHK_APP | NVME_RW_PRINFO_PRCHK_REF;
+
+    #[test]
+    fn pi_generate_then_verify() {
+        let id_ns = pi_ns(1, true);
+        let prot = pi_prot(PI_CHECKS);
+        let mut buf = vec![0u8; 2 * 520];
+        buf[..512].fill(0xa5);
+        buf[520..1032].fill(0x5a);
+
+        nvme_pi_generate(&id_ns, 100, &mut buf, None, &prot, 2);
+        let pi = NvmePi::parse(&buf[512..520]);
+        assert_eq!(pi.guard, nvme_crc16_t10dif(0, &buf[..512]));
+        assert_eq!(pi.apptag, 0x1234);
+        // type 1 reftag is the lower 32 bits of the lba
+        assert_eq!(pi.reftag, 100);
+        assert_eq!(NvmePi::parse(&buf[1032..]).reftag, 101);
+
+        assert_eq!(nvme_pi_verify(&id_ns, 100, &mut buf, None, &prot, 2), Ok(()));
+    }
+
+    #[test]
+    fn pi_verify_detects_corruption() {
+        let id_ns = pi_ns(1, true);
+        let prot = pi_prot(PI_CHECKS);
+        let mut buf = vec![0u8; 2 * 520];
+        nvme_pi_generate(&id_ns, 100, &mut buf, None, &prot, 2);
+
+        buf[600] ^= 1;
+        assert_eq!(
+            nvme_pi_verify(&id_ns, 100, &mut buf, None, &prot, 2),
+            Err(NvmeError::PiCheckFailed { lba: 101 })
+        );
+        buf[600] ^= 1;
+        // wrong starting lba fails the reftag check of the first block
+        assert_eq!(
+            nvme_pi_verify(&id_ns, 200, &mut buf, None, &prot, 2),
+            Err(NvmeError::PiCheckFailed { lba: 200 })
+        );
+    }
+
+    #[test]
+    fn pi_verify_only_requested_checks() {
+        let id_ns = pi_ns(1, true);
+        let mut buf = vec![0u8; 2 * 520];
+        nvme_pi_generate(&id_ns, 100, &mut buf, None, &pi_prot(PI_CHECKS), 2);
+
+        // corrupt data only fails with the guard check
+        buf[600] ^= 1;
+        let prot = pi_prot(NVME_RW_PRINFO_PRCHK_APP | NVME_RW_PRINFO_PRCHK_REF);
+        assert_eq!(nvme_pi_verify(&id_ns, 100, &mut buf, None, &prot, 2), Ok(()));
+        buf[600] ^= 1;
+        // a wrong starting lba only fails with the reftag check
+        let prot = pi_prot(NVME_RW_PRINFO_PRCHK_GUARD | NVME_RW_PRINFO_PRCHK_APP);
+        assert_eq!(nvme_pi_verify(&id_ns, 200, &mut buf, None, &prot, 2), Ok(()));
+        assert_eq!(nvme_pi_verify(&id_ns, 200, &mut buf, None, &pi_prot(0), 2), Ok(()));
+    }
+
+    #[test]
+    fn pi_separate_metadata_type2() {
+        let id_ns = pi_ns(2, false);
+        let prot = pi_prot(PI_CHECKS);
+        let mut buf = vec![0x11u8; 2 * 512];
+        let mut meta = vec![0u8; 2 * 8];
+
+        nvme_pi_generate(&id_ns, 0, &mut buf, Some(&mut meta), &prot, 2);
+        // type 2 reftag counts up from the initial reftag
+        assert_eq!(NvmePi::parse(&meta[..8]).reftag, 7);
+        assert_eq!(NvmePi::parse(&meta[8..]).reftag, 8);
+        assert_eq!(nvme_pi_verify(&id_ns, 0, &mut buf, Some(&mut meta), &prot, 2), Ok(()));
+    }
+
+    #[test]
+    fn pi_escape_apptag_skips_checks() {
+        let id_ns = pi_ns(1, true);
+        let prot = pi_prot(PI_CHECKS);
+        let mut buf = vec![0u8; 520];
+        NvmePi { guard: 0, apptag: NVME_PI_APPTAG_ESCAPE, reftag: 0 }.write_to(&mut buf[512..]);
+        assert_eq!(nvme_pi_verify(&id_ns, 100, &mut buf, None, &prot, 1), Ok(()));
+    }
+
+    #[test]
+    fn log_chunk_max() {
+        assert_eq!(nvme_log_chunk_max(128 * 1024, true), 128 * 1024);
+        // nvme 1.2 controllers take 4096 dwords per command
+        assert_eq!(nvme_log_chunk_max(128 * 1024, false), 16 * 1024);
+        assert_eq!(nvme_log_chunk_max(8 * 1024, false), 8 * 1024);
+    }
+}
@@ -1,3 +1,7 @@
+use alloc::vec::Vec;
+
+use super::nvme_queue::PAGE_SIZE;
+
 // #[derive(Clone, Copy)]
 // #[repr(C, packed)]
 // pub(crate) union NvmeCommand {
@@ -159,6 +163,42 @@ impl NvmeCreateSq {
     }
 }
 
+pub const NVME_ADMIN_DELETE_SQ: u8 = 0x00;
+pub const NVME_ADMIN_DELETE_CQ: u8 = 0x04;
+// cdw10: bit 15:0 sqid, bit 31:16 cid. dword0 bit 0 of the completion is set
+// when the command was not aborted
+pub const NVME_ADMIN_ABORT: u8 = 0x08;
+
+// doorbell buffer config: prp1 shadow doorbell buffer, prp2 eventidx buffer, one page each
+// laid out like the doorbell registers
+pub const NVME_ADMIN_DBBUF: u8 = 0x7c;
+
+// identify controller oacs field
+pub const NVME_CTRL_OACS_DBBUF: u16 = 1 << 8;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeDeleteQueue {
+    pub opcode: u8,
+    pub flags: u8,
+    pub command_id: u16,
+    pub nsid: u32,
+    pub rsvd1: [u32; 8],
+    pub qid: u16,
+    pub rsvd10: u16,
+    pub rsvd11: [u32; 5],
+}
+
+impl NvmeDeleteQueue {
+    pub fn new(opcode: u8, qid: u16) -> Self {
+        Self {
+            opcode,
+            qid,
+            ..Default::default()
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default)]
 pub struct NvmeRWCommand {
@@ -245,13 +285,87 @@ impl NvmeFeatures {
             rsvd2: [0; 2],
             prp1: 0,
             prp2: 0,
-            fid: fid,
-            dword11: dword11,
+            fid,
+            dword11,
             rsvd12: [0; 4],
         }
     }
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NvmeCopyCommand {
+    pub opcode: u8,
+    pub flags: u8,
+    pub command_id: u16,
+    pub nsid: u32,
+    pub rsvd2: u64,
+    pub metadata: u64,
+    pub prp1: u64,
+    pub prp2: u64,
+    // destination start lba
+    pub sdlba: u64,
+    // number of source ranges, 0's based
+    pub nr: u8,
+    // bit 0-3: descriptor format, bit 4-7: PRINFOR
+    pub format: u8,
+    // STCW, PRINFOW, FUA, LR
+    pub control: u16,
+    pub dspec: u32,
+    pub lbtrt: u32,
+    pub lbat: u16,
+    pub lbatm: u16,
+}
+
+impl NvmeCopyCommand {
+    pub fn new() -> Self {
+        Self {
+            opcode: NVME_CMD_COPY,
+            flags: 0,
+            command_id: 0,
+            nsid: 0,
+            rsvd2: 0,
+            metadata: 0,
+            prp1: 0,
+            prp2: 0,
+            sdlba: 0,
+            nr: 0,
+            format: NVME_COPY_DESC_FORMAT0,
+            control: 0,
+            dspec: 0,
+            lbtrt: 0,
+            lbat: 0,
+            lbatm: 0,
+        }
+    }
+}
+
+// source range entry, descriptor format 0
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+//32B
+pub struct NvmeSourceRange {
+    pub rsvd0: u64,
+    pub slba: u64,
+    // number of logical blocks, 0's based
+    pub nlb: u16,
+    pub rsvd18: u16,
+    pub eilbrt: u32,
+    pub elbat: u16,
+    pub elbatm: u16,
+    pub rsvd28: u32,
+}
+
+impl NvmeSourceRange {
+    pub fn new(slba: u64, nlb: u16) -> Self {
+        Self {
+            slba,
+            nlb,
+            ..Default::default()
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Default)]
 pub struct NvmeCompletion {
@@ -296,15 +410,31 @@ pub const NVME_REG_PMRSWTP: usize = 0x0e10; /* Persistent Memory Region Sustaine
 pub const NVME_REG_DBS: usize = 0x1000; /* SQ 0 Tail Doorbell */
 
 // NVME CONST
+// CAP.AMS: weighted round robin with urgent priority class supported
+pub const NVME_CAP_AMS_WRRU: u64 = 1 << 17;
+// CAP.BPS: boot partitions supported
+pub const NVME_CAP_BPS: u64 = 1 << 45;
+// CAP.NSSRS: nvm subsystem reset supported
+pub const NVME_CAP_NSSRS: u64 = 1 << 36;
+// CAP.CSS: nvm command set, one or more i/o command sets
+pub const NVME_CAP_CSS_NVM: u64 = 1 << 37;
+pub const NVME_CAP_CSS_CSI: u64 = 1 << 43;
+pub const NVME_CAP_CSS_NO_IO: u64 = 1 << 44;
+// "NVMe", written to NSSR to reset the subsystem
+pub const NVME_NSSR_MAGIC: u32 = 0x4e564d65;
 pub const NVME_CC_ENABLE: u32 = 1 << 0;
 pub const NVME_CC_CSS_NVM: u32 = 0 << 4;
+pub const NVME_CC_CSS_CSI: u32 = 6 << 4;
+pub const NVME_CC_CSS_MASK: u32 = 7 << 4;
 pub const NVME_CC_MPS_SHIFT: u32 = 7;
 pub const NVME_CC_ARB_RR: u32 = 0 << 11;
 pub const NVME_CC_ARB_WRRU: u32 = 1 << 11;
 pub const NVME_CC_ARB_VS: u32 = 7 << 11;
+pub const NVME_CC_ARB_MASK: u32 = 7 << 11;
 pub const NVME_CC_SHN_NONE: u32 = 0 << 14;
 pub const NVME_CC_SHN_NORMAL: u32 = 1 << 14;
 pub const NVME_CC_SHN_ABRUPT: u32 = 2 << 14;
+pub const NVME_CC_SHN_MASK: u32 = 3 << 14;
 pub const NVME_CC_IOSQES: u32 = 6 << 16;
 pub const NVME_CC_IOCQES: u32 = 4 << 20;
 pub const NVME_CSTS_RDY: u32 = 1 << 0;
@@ -312,6 +442,34 @@ pub const NVME_CSTS_CFS: u32 = 1 << 1;
 pub const NVME_CSTS_SHST_NORMAL: u32 = 0 << 2;
 pub const NVME_CSTS_SHST_OCCUR: u32 = 1 << 2;
 pub const NVME_CSTS_SHST_CMPLT: u32 = 2 << 2;
+pub const NVME_CSTS_SHST_MASK: u32 = 3 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeShutdown {
+    // i/o queues are drained and deleted before CC.SHN is set
+    Normal,
+    // CC.SHN is set right away, outstanding commands are lost
+    Abrupt,
+}
+
+// what happens when the controller reports a fatal status (CSTS.CFS)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeRecoveryPolicy {
+    // commands fail with NvmeError::ControllerFatal, the caller decides when to recover
+    Manual,
+    // reset the controller, commands in flight fail with NvmeError::ControllerFatal
+    FailInflight,
+    // reset the controller and submit the commands in flight again
+    Resubmit,
+}
+
+// platform hook for a pcie function level reset, used when the controller does not
+// respond to a controller reset
+pub trait NvmePciReset: Send {
+    // initiate the flr, wait for it to finish and restore the config space (bars, bus
+    // master enable). return false if the function could not be reset
+    fn function_level_reset(&mut self) -> bool;
+}
 
 pub const NVME_QUEUE_PHYS_CONTIG: u16 = 1 << 0;
 pub const NVME_CQ_IRQ_ENABLED: u16 = 1 << 1;
@@ -320,6 +478,178 @@ pub const NVME_SQ_PRIO_HIGH: u16 = 1 << 1;
 pub const NVME_SQ_PRIO_MEDIUM: u16 = 2 << 1;
 pub const NVME_SQ_PRIO_LOW: u16 = 3 << 1;
 
+// submission queue priority class, only honoured under weighted round robin arbitration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeQueuePriority {
+    // served before any other class
+    Urgent,
+    High,
+    Medium,
+    Low,
+}
+
+impl NvmeQueuePriority {
+    pub fn sq_flags(&self) -> u16 {
+        match self {
+            NvmeQueuePriority::Urgent => NVME_SQ_PRIO_URGENT,
+            NvmeQueuePriority::High => NVME_SQ_PRIO_HIGH,
+            NvmeQueuePriority::Medium => NVME_SQ_PRIO_MEDIUM,
+            NvmeQueuePriority::Low => NVME_SQ_PRIO_LOW,
+        }
+    }
+}
+
+// controller memory buffer
+pub const NVME_CMBSZ_SQS: u32 = 1 << 0;
+pub const NVME_CMBSZ_CQS: u32 = 1 << 1;
+pub const NVME_CMBSZ_LISTS: u32 = 1 << 2;
+pub const NVME_CMBSZ_RDS: u32 = 1 << 3;
+pub const NVME_CMBSZ_WDS: u32 = 1 << 4;
+// capabilities registers enabled, controller memory space enabled
+pub const NVME_CMBMSC_CRE: u64 = 1 << 0;
+pub const NVME_CMBMSC_CMSE: u64 = 1 << 1;
+
+// CMBLOC / CMBSZ
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NvmeCmbInfo {
+    // pci bar holding the cmb
+    pub bir: u8,
+    // offset of the cmb in the bar, in bytes
+    pub offset: u64,
+    pub size: u64,
+    // NVME_CMBSZ_* bits
+    pub flags: u32,
+}
+
+impl NvmeCmbInfo {
+    pub fn from_regs(cmbloc: u32, cmbsz: u32) -> Self {
+        // size unit: 4KiB, 64KiB, 1MiB ...
+        let unit = 4096u64 << (4 * ((cmbsz >> 8) & 0xf));
+        Self {
+            bir: (cmbloc & 0x7) as u8,
+            offset: (cmbloc >> 12) as u64 * unit,
+            size: (cmbsz >> 12) as u64 * unit,
+            flags: cmbsz & 0x1f,
+        }
+    }
+}
+
+// what the driver places in the controller memory buffer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NvmeCmbUsage {
+    // io submission queues
+    pub sq: bool,
+    // prp lists of every queue
+    pub prp_lists: bool,
+    // admin and io command data buffers
+    pub data: bool,
+}
+
+// boot partition read status (BPINFO.BRS)
+pub const NVME_BPINFO_BRS_NONE: u8 = 0;
+pub const NVME_BPINFO_BRS_READING: u8 = 1;
+pub const NVME_BPINFO_BRS_SUCCESS: u8 = 2;
+pub const NVME_BPINFO_BRS_ERROR: u8 = 3;
+
+// boot partition reads are in 4KiB units, BPRSEL.BPRSZ is 10 bits wide
+pub const NVME_BP_READ_UNIT: usize = 4096;
+pub const NVME_BP_READ_MAX_UNITS: usize = 0x3ff;
+
+// CAP.CSS
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NvmeCssCap {
+    // nvm command set
+    pub nvm: bool,
+    // one or more i/o command sets, selected with NVME_CC_CSS_CSI
+    pub io_sets: bool,
+    // only the admin command set, no i/o command set
+    pub no_io: bool,
+}
+
+impl NvmeCssCap {
+    pub fn from_cap(cap: u64) -> Self {
+        Self {
+            nvm: cap & NVME_CAP_CSS_NVM != 0,
+            io_sets: cap & NVME_CAP_CSS_CSI != 0,
+            no_io: cap & NVME_CAP_CSS_NO_IO != 0,
+        }
+    }
+}
+
+// BPINFO
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NvmeBootPartitionInfo {
+    // size of each boot partition in bytes
+    pub size: usize,
+    // NVME_BPINFO_BRS_*
+    pub brs: u8,
+    // active boot partition id
+    pub active: u8,
+}
+
+impl NvmeBootPartitionInfo {
+    pub fn from_reg(bpinfo: u32) -> Self {
+        Self {
+            // 128KiB units
+            size: (bpinfo & 0x7fff) as usize * 128 * 1024,
+            brs: ((bpinfo >> 24) & 0x3) as u8,
+            active: (bpinfo >> 31) as u8,
+        }
+    }
+}
+
+// persistent memory region
+pub const NVME_PMRCTL_EN: u32 = 1 << 0;
+pub const NVME_PMRSTS_NRDY: u32 = 1 << 8;
+// write barrier mechanisms: a read from the pmr / from PMRSTS makes earlier writes persistent
+pub const NVME_PMRWBM_PMR_READ: u8 = 1 << 0;
+pub const NVME_PMRWBM_STS_READ: u8 = 1 << 1;
+
+// PMRCAP / PMREBS / PMRSWTP
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NvmePmrInfo {
+    // pci bar holding the pmr, the whole bar is the pmr
+    pub bir: u8,
+    // read / write data support
+    pub rds: bool,
+    pub wds: bool,
+    // NVME_PMRWBM_* bits
+    pub wbm: u8,
+    // time for the pmr to become ready or not ready
+    pub timeout_us: u64,
+    // controller memory space supported
+    pub cmss: bool,
+    // elasticity buffer size in bytes, 0 if not reported
+    pub ebs: u64,
+    // sustained write throughput in bytes per second, 0 if not reported
+    pub swt: u64,
+    // reads may bypass the elasticity buffer
+    pub rbb: bool,
+}
+
+impl NvmePmrInfo {
+    pub fn from_regs(pmrcap: u32, pmrebs: u32, pmrswtp: u32) -> Self {
+        // PMRTO is in 500ms or minute units
+        let unit_us = if (pmrcap >> 8) & 0x3 == 0 { 500 * 1000 } else { 60 * 1000 * 1000 };
+        // bytes, KiB, MiB, GiB
+        let scale = |units: u32| match units & 0xf {
+            0..=3 => 1u64 << (10 * (units & 0xf)),
+            _ => 0,
+        };
+        Self {
+            bir: ((pmrcap >> 5) & 0x7) as u8,
+            rds: pmrcap & (1 << 3) != 0,
+            wds: pmrcap & (1 << 4) != 0,
+            wbm: ((pmrcap >> 10) & 0xf) as u8,
+            timeout_us: ((pmrcap >> 16) & 0xff) as u64 * unit_us,
+            cmss: pmrcap & (1 << 24) != 0,
+            ebs: (pmrebs >> 8) as u64 * scale(pmrebs),
+            swt: (pmrswtp >> 8) as u64 * scale(pmrswtp),
+            rbb: pmrebs & (1 << 4) != 0,
+        }
+    }
+}
+
 // nvme feature command fid field
 pub const NVME_FEAT_ARBITRATION: u32 = 0x01;
 pub const NVME_FEAT_POWER_MGMT: u32 = 0x02;
@@ -332,4 +662,2296 @@ pub const NVME_FEAT_IRQ_COALESCE: u32 = 0x08;
 pub const NVME_FEAT_IRQ_CONFIG: u32 = 0x09;
 pub const NVME_FEAT_WRITE_ATOMIC: u32 = 0x0a;
 pub const NVME_FEAT_ASYNC_EVENT: u32 = 0x0b;
-pub const NVME_FEAT_SW_PROGRESS: u32 = 0x0c;
+pub const NVME_FEAT_AUTO_PST: u32 = 0x0c;
+pub const NVME_FEAT_TIMESTAMP: u32 = 0x0e;
+pub const NVME_FEAT_HOST_BEHAVIOR: u32 = 0x16;
+pub const NVME_FEAT_IOCS_PROFILE: u32 = 0x19;
+pub const NVME_FEAT_SW_PROGRESS: u32 = 0x80;
+pub const NVME_FEAT_HOST_ID: u32 = 0x81;
+pub const NVME_FEAT_RESV_MASK: u32 = 0x82;
+pub const NVME_FEAT_RESV_PERSIST: u32 = 0x83;
+
+// nvm command set opcode
+pub const NVME_CMD_WRITE: u8 = 0x01;
+pub const NVME_CMD_READ: u8 = 0x02;
+pub const NVME_CMD_COPY: u8 = 0x19;
+
+// copy command descriptor format
+pub const NVME_COPY_DESC_FORMAT0: u8 = 0x0;
+
+// identify command cns field
+pub const NVME_ID_CNS_NS: u8 = 0x00;
+pub const NVME_ID_CNS_CTRL: u8 = 0x01;
+pub const NVME_ID_CNS_NS_ACTIVE_LIST: u8 = 0x02;
+pub const NVME_ID_CNS_NS_DESC_LIST: u8 = 0x03;
+pub const NVME_ID_CNS_CS_NS: u8 = 0x05;
+pub const NVME_ID_CNS_CS_CTRL: u8 = 0x06;
+pub const NVME_ID_CNS_CS_NS_ACTIVE_LIST: u8 = 0x07;
+pub const NVME_ID_CNS_IOCS: u8 = 0x1c;
+
+// command set identifier
+pub const NVME_CSI_NVM: u8 = 0x00;
+pub const NVME_CSI_KV: u8 = 0x01;
+pub const NVME_CSI_ZNS: u8 = 0x02;
+
+// namespace identification descriptor type
+pub const NVME_NIDT_EUI64: u8 = 0x1;
+pub const NVME_NIDT_NGUID: u8 = 0x2;
+pub const NVME_NIDT_UUID: u8 = 0x3;
+pub const NVME_NIDT_CSI: u8 = 0x4;
+
+// namespace identification descriptor list (cns 0x03)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NvmeNsIds {
+    pub eui64: Option<[u8; 8]>,
+    pub nguid: Option<[u8; 16]>,
+    pub uuid: Option<[u8; 16]>,
+    // command set of the namespace, nvm when the controller reports none
+    pub csi: u8,
+}
+
+impl NvmeNsIds {
+    pub fn parse(data: &[u8]) -> Self {
+        let mut ids = Self::default();
+        let mut offset = 0;
+        // 4 byte header of type and length, then the identifier
+        while offset + 4 <= data.len() && data[offset] != 0 {
+            let (nidt, nidl) = (data[offset], data[offset + 1] as usize);
+            let nid = &data[offset + 4..(offset + 4 + nidl).min(data.len())];
+            match (nidt, nid.len()) {
+                (NVME_NIDT_EUI64, 8) => ids.eui64 = nid.try_into().ok(),
+                (NVME_NIDT_NGUID, 16) => ids.nguid = nid.try_into().ok(),
+                (NVME_NIDT_UUID, 16) => ids.uuid = nid.try_into().ok(),
+                (NVME_NIDT_CSI, 1) => ids.csi = nid[0],
+                _ => {}
+            }
+            offset += 4 + nidl;
+        }
+        ids
+    }
+}
+
+// identify i/o command set data structure (cns 0x1c), each vector is a bitmap of
+// the command sets of a profile, bit n for csi n. the vector index is the profile
+// selected through NVME_FEAT_IOCS_PROFILE
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NvmeIdIocs {
+    pub vectors: Vec<u64>,
+}
+
+impl NvmeIdIocs {
+    pub const MAX_VECTORS: usize = 512;
+
+    pub fn parse(data: &[u8]) -> Self {
+        let mut vectors: Vec<u64> = (0..Self::MAX_VECTORS).map(|i| le64(data, i * 8)).collect();
+        while vectors.last() == Some(&0) {
+            vectors.pop();
+        }
+        Self { vectors }
+    }
+
+    // first profile that includes every command set in csis
+    pub fn find(&self, csis: &[u8]) -> Option<u16> {
+        let want = csis.iter().fold(0, |acc, csi| acc | 1u64 << csi);
+        self.vectors.iter().position(|v| v & want == want).map(|i| i as u16)
+    }
+}
+
+// identify controller oncs field
+pub const NVME_CTRL_ONCS_COPY: u16 = 1 << 8;
+// identify controller ocfs field
+pub const NVME_CTRL_OCFS_FORMAT0: u16 = 1 << 0;
+
+pub(crate) fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+pub(crate) fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+pub(crate) fn le64(data: &[u8], offset: usize) -> u64 {
+    le32(data, offset) as u64 | (le32(data, offset + 4) as u64) << 32
+}
+
+pub(crate) fn le128(data: &[u8], offset: usize) -> u128 {
+    le64(data, offset) as u128 | (le64(data, offset + 8) as u128) << 64
+}
+
+// identify controller data structure (cns 0x01), only the fields the driver uses
+#[derive(Debug, Clone, Copy)]
+pub struct NvmeIdCtrl {
+    pub vid: u16,
+    pub ssvid: u16,
+    pub sn: [u8; 20],
+    pub mn: [u8; 40],
+    pub fr: [u8; 8],
+    // maximum data transfer size, in units of the minimum page size, 0 = no limit
+    pub mdts: u8,
+    pub cntlid: u16,
+    pub ver: u32,
+    // rtd3 entry latency, in microseconds
+    pub rtd3e: u32,
+    // optional asynchronous events supported
+    pub oaes: u32,
+    pub oacs: u16,
+    // asynchronous event request limit, 0's based
+    pub aerl: u8,
+    // firmware updates
+    pub frmw: u8,
+    // log page attributes
+    pub lpa: u8,
+    // error log page entries, 0's based
+    pub elpe: u8,
+    // number of power states supported, 0's based
+    pub npss: u8,
+    // autonomous power state transition attributes
+    pub apsta: u8,
+    // sanitize capabilities
+    pub sanicap: u32,
+    // firmware update granularity, in 4KiB units
+    pub fwug: u8,
+    pub nn: u32,
+    pub oncs: u16,
+    // format nvm attributes
+    pub fna: u8,
+    pub ocfs: u16,
+    // power state descriptors, only the first npss + 1 are valid
+    pub psd: [NvmePowerState; 32],
+}
+
+impl NvmeIdCtrl {
+    pub fn parse(data: &[u8]) -> Self {
+        let mut sn = [0u8; 20];
+        let mut mn = [0u8; 40];
+        let mut fr = [0u8; 8];
+        sn.copy_from_slice(&data[4..24]);
+        mn.copy_from_slice(&data[24..64]);
+        fr.copy_from_slice(&data[64..72]);
+
+        let mut psd = [NvmePowerState::default(); 32];
+        for (i, ps) in psd.iter_mut().enumerate().take(data[263] as usize + 1) {
+            *ps = NvmePowerState::parse(&data[2048 + i * NvmePowerState::SIZE..]);
+        }
+
+        Self {
+            vid: le16(data, 0),
+            ssvid: le16(data, 2),
+            sn,
+            mn,
+            fr,
+            mdts: data[77],
+            cntlid: le16(data, 78),
+            ver: le32(data, 80),
+            rtd3e: le32(data, 88),
+            oaes: le32(data, 92),
+            oacs: le16(data, 256),
+            aerl: data[259],
+            frmw: data[260],
+            lpa: data[261],
+            elpe: data[262],
+            npss: data[263],
+            apsta: data[265],
+            sanicap: le32(data, 328),
+            fwug: data[319],
+            nn: le32(data, 516),
+            oncs: le16(data, 520),
+            fna: data[524],
+            ocfs: le16(data, 534),
+            psd,
+        }
+    }
+
+    pub fn power_states(&self) -> &[NvmePowerState] {
+        &self.psd[..(self.npss as usize + 1).min(self.psd.len())]
+    }
+}
+
+// apsta
+pub const NVME_CTRL_APSTA_APST: u8 = 1 << 0;
+
+// power state descriptor, 32 bytes each
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NvmePowerState {
+    // maximum power, in 0.01W units, or 0.0001W units if NVME_PS_FLAGS_MAX_POWER_SCALE is set
+    pub max_power: u16,
+    pub flags: u8,
+    // entry / exit latency, in microseconds
+    pub entry_lat: u32,
+    pub exit_lat: u32,
+    // relative read / write throughput and latency, lower is better
+    pub read_tput: u8,
+    pub read_lat: u8,
+    pub write_tput: u8,
+    pub write_lat: u8,
+    pub idle_power: u16,
+    // idle power scale, 1 = 0.0001W, 2 = 0.01W
+    pub idle_scale: u8,
+    pub active_power: u16,
+    // active power workload
+    pub active_work_scale: u8,
+}
+
+pub const NVME_PS_FLAGS_MAX_POWER_SCALE: u8 = 1 << 0;
+pub const NVME_PS_FLAGS_NON_OP_STATE: u8 = 1 << 1;
+
+impl NvmePowerState {
+    pub const SIZE: usize = 32;
+
+    pub fn parse(data: &[u8]) -> Self {
+        Self {
+            max_power: le16(data, 0),
+            flags: data[3],
+            entry_lat: le32(data, 4),
+            exit_lat: le32(data, 8),
+            read_tput: data[12] & 0x1f,
+            read_lat: data[13] & 0x1f,
+            write_tput: data[14] & 0x1f,
+            write_lat: data[15] & 0x1f,
+            idle_power: le16(data, 16),
+            idle_scale: data[18] >> 6,
+            active_power: le16(data, 20),
+            active_work_scale: data[22],
+        }
+    }
+
+    // the controller does not process i/o commands in a non-operational state
+    pub fn non_operational(&self) -> bool {
+        self.flags & NVME_PS_FLAGS_NON_OP_STATE != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeLbaFormat {
+    // metadata size
+    pub ms: u16,
+    // lba data size, 2^n bytes
+    pub lbads: u8,
+    // relative performance
+    pub rp: u8,
+}
+
+// lba formats of a namespace, 16 before nvme 2.0
+pub const NVME_NS_LBAF_MAX: usize = 64;
+
+// identify namespace data structure (cns 0x00), only the fields the driver uses
+#[derive(Debug, Clone, Copy)]
+pub struct NvmeIdNs {
+    pub nsze: u64,
+    pub ncap: u64,
+    pub nuse: u64,
+    pub nsfeat: u8,
+    pub nlbaf: u8,
+    pub flbas: u8,
+    pub mc: u8,
+    pub dpc: u8,
+    pub dps: u8,
+    pub nmic: u8,
+    pub rescap: u8,
+    // maximum single source range length
+    pub mssrl: u16,
+    // maximum copy length
+    pub mcl: u32,
+    // maximum source range count, 0's based
+    pub msrc: u8,
+    pub lbaf: [NvmeLbaFormat; NVME_NS_LBAF_MAX],
+}
+
+impl Default for NvmeIdNs {
+    fn default() -> Self {
+        Self::parse(&[0; 128 + NVME_NS_LBAF_MAX * 4])
+    }
+}
+
+impl NvmeIdNs {
+    pub fn parse(data: &[u8]) -> Self {
+        let mut lbaf = [NvmeLbaFormat::default(); NVME_NS_LBAF_MAX];
+        for (i, f) in lbaf.iter_mut().enumerate() {
+            let offset = 128 + i * 4;
+            f.ms = le16(data, offset);
+            f.lbads = data[offset + 2];
+            f.rp = data[offset + 3] & 0x3;
+        }
+
+        Self {
+            nsze: le64(data, 0),
+            ncap: le64(data, 8),
+            nuse: le64(data, 16),
+            nsfeat: data[24],
+            nlbaf: data[25],
+            flbas: data[26],
+            mc: data[27],
+            dpc: data[28],
+            dps: data[29],
+            nmic: data[30],
+            rescap: data[31],
+            mssrl: le16(data, 74),
+            mcl: le32(data, 76),
+            msrc: data[80],
+            lbaf,
+        }
+    }
+
+    // host built payload for namespace management create
+    pub fn write_create_data(&self, data: &mut [u8]) {
+        data[..PAGE_SIZE].fill(0);
+        data[0..8].copy_from_slice(&self.nsze.to_le_bytes());
+        data[8..16].copy_from_slice(&self.ncap.to_le_bytes());
+        data[26] = self.flbas;
+        data[29] = self.dps;
+        data[30] = self.nmic;
+    }
+
+    // index of the lba format in use, FLBAS bits 3:0 and bits 6:5 as the upper bits
+    pub fn lba_format_index(&self) -> usize {
+        (self.flbas & 0xf) as usize | (((self.flbas >> 5) & 0x3) as usize) << 4
+    }
+
+    // lba format currently in use
+    pub fn lba_format(&self) -> NvmeLbaFormat {
+        self.lbaf[self.lba_format_index()]
+    }
+
+    pub fn lba_size(&self) -> usize {
+        1 << self.lba_format().lbads
+    }
+
+    // a single Copy command may move the ranges (MSRC, MSSRL, MCL)
+    pub fn copy_within_limits(&self, ranges: &[NvmeSourceRange]) -> bool {
+        // nr field is 8 bits, 0's based
+        if ranges.len() > 256 || ranges.len() > self.msrc as usize + 1 {
+            return false;
+        }
+
+        let mut total = 0_u64;
+        for range in ranges {
+            let nlb = range.nlb as u64 + 1;
+            if nlb > self.mssrl as u64 {
+                return false;
+            }
+            total += nlb;
+        }
+        total <= self.mcl as u64
+    }
+
+    pub fn metadata_size(&self) -> usize {
+        self.lba_format().ms as usize
+    }
+
+    // metadata is transferred at the end of each lba instead of a separate buffer
+    pub fn extended_lba(&self) -> bool {
+        self.flbas & NVME_NS_FLBAS_META_EXT != 0
+    }
+
+    pub fn pi_type(&self) -> NvmePiType {
+        match self.dps & NVME_NS_DPS_PI_MASK {
+            1 => NvmePiType::Type1,
+            2 => NvmePiType::Type2,
+            3 => NvmePiType::Type3,
+            _ => NvmePiType::None,
+        }
+    }
+
+    // protection information is the first 8 bytes of metadata, otherwise the last 8 bytes
+    pub fn pi_first(&self) -> bool {
+        self.dps & NVME_NS_DPS_PI_FIRST != 0
+    }
+}
+
+// identify namespace flbas and dps fields
+pub const NVME_NS_FLBAS_META_EXT: u8 = 1 << 4;
+pub const NVME_NS_DPS_PI_MASK: u8 = 0x7;
+pub const NVME_NS_DPS_PI_FIRST: u8 = 1 << 3;
+
+// read/write command dword12 protection information field
+pub const NVME_RW_PRINFO_PRCHK_REF: u16 = 1 << 10;
+pub const NVME_RW_PRINFO_PRCHK_APP: u16 = 1 << 11;
+pub const NVME_RW_PRINFO_PRCHK_GUARD: u16 = 1 << 12;
+pub const NVME_RW_PRINFO_PRACT: u16 = 1 << 13;
+
+// media status of end-to-end protection check failures
+pub const NVME_SC_GUARD_CHECK: u8 = 0x82;
+pub const NVME_SC_APPTAG_CHECK: u8 = 0x83;
+pub const NVME_SC_REFTAG_CHECK: u8 = 0x84;
+
+// apptag of all 1s disables checking of a block, type 3 also needs reftag of all 1s
+pub const NVME_PI_APPTAG_ESCAPE: u16 = 0xffff;
+pub const NVME_PI_REFTAG_ESCAPE: u32 = 0xffff_ffff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmePiType {
+    None,
+    // reftag is the lower 32 bits of the lba
+    Type1,
+    // reftag starts at the value given in the command
+    Type2,
+    // reftag is not checked
+    Type3,
+}
+
+// 8 byte protection information tuple, stored big endian in the metadata
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NvmePi {
+    pub guard: u16,
+    pub apptag: u16,
+    pub reftag: u32,
+}
+
+impl NvmePi {
+    pub const SIZE: usize = 8;
+
+    pub fn parse(data: &[u8]) -> Self {
+        Self {
+            guard: u16::from_be_bytes([data[0], data[1]]),
+            apptag: u16::from_be_bytes([data[2], data[3]]),
+            reftag: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+        }
+    }
+
+    pub fn write_to(&self, data: &mut [u8]) {
+        data[0..2].copy_from_slice(&self.guard.to_be_bytes());
+        data[2..4].copy_from_slice(&self.apptag.to_be_bytes());
+        data[4..8].copy_from_slice(&self.reftag.to_be_bytes());
+    }
+}
+
+// guard crc of T10 DIF, polynomial 0x8bb7, no reflection, initial value 0
+pub fn nvme_crc16_t10dif(mut crc: u16, data: &[u8]) -> u16 {
+    for b in data {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { crc << 1 ^ 0x8bb7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+// protection settings of a read or write
+// checks are NVME_RW_PRINFO_PRCHK_* bits. with pract the controller inserts the
+// protection information on write and strips it on read when metadata is only the
+// 8 byte tuple. reftag is the initial reftag of type 2, ignored for type 1 which uses the lba
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeProtection {
+    pub pract: bool,
+    pub checks: u16,
+    pub apptag: u16,
+    pub appmask: u16,
+    pub reftag: u32,
+}
+
+// reservation opcode
+pub const NVME_CMD_RESV_REGISTER: u8 = 0x0d;
+pub const NVME_CMD_RESV_REPORT: u8 = 0x0e;
+pub const NVME_CMD_RESV_ACQUIRE: u8 = 0x11;
+pub const NVME_CMD_RESV_RELEASE: u8 = 0x15;
+
+// identify controller oncs field
+pub const NVME_CTRL_ONCS_RESERVATIONS: u16 = 1 << 5;
+
+// reservation command dword10 ignore existing key
+pub const NVME_RESV_IEKEY: u32 = 1 << 3;
+// reservation report dword11 extended data structure
+pub const NVME_RESV_REPORT_EDS: u32 = 1 << 0;
+
+// host identifier feature dword11 extended host identifier
+pub const NVME_HOST_ID_EXHID: u32 = 1 << 0;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeReservationType {
+    WriteExclusive = 1,
+    ExclusiveAccess = 2,
+    WriteExclusiveRegistrantsOnly = 3,
+    ExclusiveAccessRegistrantsOnly = 4,
+    WriteExclusiveAllRegistrants = 5,
+    ExclusiveAccessAllRegistrants = 6,
+}
+
+impl NvmeReservationType {
+    pub fn from_u8(rtype: u8) -> Option<Self> {
+        match rtype {
+            1 => Some(Self::WriteExclusive),
+            2 => Some(Self::ExclusiveAccess),
+            3 => Some(Self::WriteExclusiveRegistrantsOnly),
+            4 => Some(Self::ExclusiveAccessRegistrantsOnly),
+            5 => Some(Self::WriteExclusiveAllRegistrants),
+            6 => Some(Self::ExclusiveAccessAllRegistrants),
+            _ => None,
+        }
+    }
+}
+
+// reservation register action (RREGA)
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeRegisterAction {
+    Register = 0,
+    Unregister = 1,
+    Replace = 2,
+}
+
+// change persist through power loss state (CPTPL)
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmePtplChange {
+    NoChange = 0,
+    Clear = 2,
+    Persist = 3,
+}
+
+// reservation acquire action (RACQA)
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeAcquireAction {
+    Acquire = 0,
+    Preempt = 1,
+    PreemptAndAbort = 2,
+}
+
+// reservation release action (RRELA)
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeReleaseAction {
+    Release = 0,
+    Clear = 1,
+}
+
+// registered controller data structure
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeRegisteredCtrl {
+    pub cntlid: u16,
+    // bit 0: holds the reservation
+    pub rcsts: u8,
+    // 64 bit host identifier is stored in the first 8 bytes
+    pub hostid: [u8; 16],
+    pub rkey: u64,
+}
+
+// reservation status data structure returned by reservation report
+#[derive(Debug, Clone, Default)]
+pub struct NvmeReservationStatus {
+    pub gen: u32,
+    pub rtype: Option<NvmeReservationType>,
+    // persist through power loss state
+    pub ptpls: u8,
+    pub regctl: Vec<NvmeRegisteredCtrl>,
+}
+
+impl NvmeReservationStatus {
+    pub fn parse(data: &[u8], extended: bool) -> Self {
+        let regctl_count = le16(data, 5) as usize;
+        let (header_size, entry_size) = if extended { (64, 64) } else { (24, 24) };
+        let count = regctl_count.min((data.len() - header_size) / entry_size);
+
+        let mut regctl = Vec::with_capacity(count);
+        for i in 0..count {
+            let entry = &data[header_size + i * entry_size..];
+            let mut ctrl = NvmeRegisteredCtrl {
+                cntlid: le16(entry, 0),
+                rcsts: entry[2],
+                ..Default::default()
+            };
+            if extended {
+                ctrl.rkey = le64(entry, 8);
+                ctrl.hostid.copy_from_slice(&entry[16..32]);
+            } else {
+                ctrl.hostid[..8].copy_from_slice(&entry[8..16]);
+                ctrl.rkey = le64(entry, 16);
+            }
+            regctl.push(ctrl);
+        }
+
+        Self {
+            gen: le32(data, 0),
+            rtype: NvmeReservationType::from_u8(data[4]),
+            ptpls: data[9],
+            regctl,
+        }
+    }
+}
+
+// admin command opcode
+pub const NVME_ADMIN_NS_MGMT: u8 = 0x0d;
+pub const NVME_ADMIN_NS_ATTACH: u8 = 0x15;
+
+// namespace management / attachment dword10 select field
+pub const NVME_NS_MGMT_SEL_CREATE: u32 = 0x0;
+pub const NVME_NS_MGMT_SEL_DELETE: u32 = 0x1;
+pub const NVME_NS_ATTACH_SEL_ATTACH: u32 = 0x0;
+pub const NVME_NS_ATTACH_SEL_DETACH: u32 = 0x1;
+
+// identify controller oacs field
+pub const NVME_CTRL_OACS_NS_MGMT: u16 = 1 << 3;
+
+// maximum number of controller identifiers in a controller list
+pub const NVME_CTRL_LIST_MAX: usize = 2047;
+
+// broadcast nsid, all namespaces
+pub const NVME_NSID_ALL: u32 = 0xffffffff;
+
+pub const NVME_ADMIN_FORMAT_NVM: u8 = 0x80;
+
+// identify controller oacs field
+pub const NVME_CTRL_OACS_FORMAT: u16 = 1 << 1;
+
+// identify controller fna field
+pub const NVME_CTRL_FNA_FORMAT_ALL: u8 = 1 << 0;
+pub const NVME_CTRL_FNA_SECURE_ALL: u8 = 1 << 1;
+pub const NVME_CTRL_FNA_CRYPTO_ERASE: u8 = 1 << 2;
+
+// format nvm dword10
+pub const NVME_FORMAT_MSET: u32 = 1 << 4;
+pub const NVME_FORMAT_PIL: u32 = 1 << 8;
+
+// format nvm secure erase settings (SES)
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeSecureErase {
+    None = 0,
+    UserData = 1,
+    Crypto = 2,
+}
+
+// format nvm dword10, the lba format index is split into bit 3:0 and bit 13:12
+pub fn nvme_format_cdw10(lbaf: u8, ses: NvmeSecureErase, pi: u8, pil: bool, mset: bool) -> u32 {
+    let mut cdw10 = (lbaf & 0xf) as u32 | ((lbaf >> 4) as u32) << 12;
+    cdw10 |= (pi as u32) << 5 | (ses as u32) << 9;
+    if mset {
+        cdw10 |= NVME_FORMAT_MSET;
+    }
+    if pil {
+        cdw10 |= NVME_FORMAT_PIL;
+    }
+    cdw10
+}
+
+pub const NVME_ADMIN_GET_LOG_PAGE: u8 = 0x02;
+pub const NVME_ADMIN_SANITIZE: u8 = 0x84;
+
+// log page identifier
+pub const NVME_LOG_ERROR: u8 = 0x01;
+pub const NVME_LOG_SMART: u8 = 0x02;
+pub const NVME_LOG_CHANGED_NS: u8 = 0x04;
+pub const NVME_LOG_SANITIZE: u8 = 0x81;
+
+// identify controller sanicap field
+pub const NVME_CTRL_SANICAP_CRYPTO: u32 = 1 << 0;
+pub const NVME_CTRL_SANICAP_BLOCK: u32 = 1 << 1;
+pub const NVME_CTRL_SANICAP_OVERWRITE: u32 = 1 << 2;
+
+// sanitize dword10
+pub const NVME_SANITIZE_AUSE: u32 = 1 << 3;
+pub const NVME_SANITIZE_OIPBP: u32 = 1 << 8;
+pub const NVME_SANITIZE_NDAS: u32 = 1 << 9;
+
+// sanitize action (SANACT)
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeSanitizeAction {
+    ExitFailureMode = 1,
+    BlockErase = 2,
+    Overwrite = 3,
+    CryptoErase = 4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeSanitizeStatus {
+    NeverSanitized,
+    Completed,
+    InProgress,
+    Failed,
+    // completed, media was not deallocated (NDAS)
+    CompletedNoDealloc,
+    Unknown(u8),
+}
+
+// sanitize status log page (lid 0x81)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeSanitizeLog {
+    // progress of the current sanitize, numerator of n / 65536
+    pub sprog: u16,
+    pub sstat: u16,
+    // dword10 of the sanitize command that started the operation
+    pub scdw10: u32,
+    // estimated time in seconds, 0xffffffff = no estimate
+    pub eto: u32,
+    pub etbe: u32,
+    pub etce: u32,
+}
+
+impl NvmeSanitizeLog {
+    pub fn parse(data: &[u8]) -> Self {
+        Self {
+            sprog: le16(data, 0),
+            sstat: le16(data, 2),
+            scdw10: le32(data, 4),
+            eto: le32(data, 8),
+            etbe: le32(data, 12),
+            etce: le32(data, 16),
+        }
+    }
+
+    pub fn status(&self) -> NvmeSanitizeStatus {
+        match (self.sstat & 0x7) as u8 {
+            0 => NvmeSanitizeStatus::NeverSanitized,
+            1 => NvmeSanitizeStatus::Completed,
+            2 => NvmeSanitizeStatus::InProgress,
+            3 => NvmeSanitizeStatus::Failed,
+            4 => NvmeSanitizeStatus::CompletedNoDealloc,
+            s => NvmeSanitizeStatus::Unknown(s),
+        }
+    }
+
+    // progress of an in progress sanitize, 0 - 100
+    pub fn progress_percent(&self) -> u8 {
+        if self.status() != NvmeSanitizeStatus::InProgress {
+            return 100;
+        }
+        (self.sprog as u32 * 100 / 65536) as u8
+    }
+
+    // number of overwrite passes completed
+    pub fn overwrite_passes(&self) -> u8 {
+        ((self.sstat >> 3) & 0x1f) as u8
+    }
+
+    // no user data has been written since the last sanitize
+    pub fn global_data_erased(&self) -> bool {
+        self.sstat & (1 << 8) != 0
+    }
+}
+
+pub const NVME_ADMIN_FW_COMMIT: u8 = 0x10;
+pub const NVME_ADMIN_FW_DOWNLOAD: u8 = 0x11;
+
+pub const NVME_LOG_FW_SLOT: u8 = 0x03;
+
+// identify controller oacs field
+pub const NVME_CTRL_OACS_FW: u16 = 1 << 2;
+
+// identify controller frmw field
+pub const NVME_CTRL_FRMW_SLOT1_RO: u8 = 1 << 0;
+pub const NVME_CTRL_FRMW_NO_RESET: u8 = 1 << 4;
+
+// firmware commit action (CA)
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeCommitAction {
+    // replace the image in the slot, do not activate
+    Replace = 0,
+    // replace and activate at the next reset
+    ReplaceAndActivate = 1,
+    // activate the existing image in the slot at the next reset
+    Activate = 2,
+    // activate immediately without reset
+    ActivateNoReset = 3,
+    ReplaceBootPartition = 6,
+    ActivateBootPartition = 7,
+}
+
+// firmware slot information log page (lid 0x03)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeFwSlotLog {
+    // active firmware info
+    pub afi: u8,
+    // firmware revision of slot 1 - 7
+    pub frs: [[u8; 8]; 7],
+}
+
+impl NvmeFwSlotLog {
+    pub fn parse(data: &[u8]) -> Self {
+        let mut frs = [[0u8; 8]; 7];
+        for (i, rev) in frs.iter_mut().enumerate() {
+            rev.copy_from_slice(&data[8 + i * 8..16 + i * 8]);
+        }
+        Self { afi: data[0], frs }
+    }
+
+    // slot the running firmware was loaded from
+    pub fn active_slot(&self) -> u8 {
+        self.afi & 0x7
+    }
+
+    // slot activated at the next reset, if any
+    pub fn next_slot(&self) -> Option<u8> {
+        match (self.afi >> 4) & 0x7 {
+            0 => None,
+            slot => Some(slot),
+        }
+    }
+
+    // firmware revision in slot 1 - 7, empty slots are all zero
+    pub fn revision(&self, slot: u8) -> &[u8] {
+        &self.frs[slot as usize - 1]
+    }
+}
+
+// identify controller lpa field
+pub const NVME_CTRL_LPA_SMART_PER_NS: u8 = 1 << 0;
+pub const NVME_CTRL_LPA_EXTENDED: u8 = 1 << 2;
+
+// smart log critical warning
+pub const NVME_SMART_CRIT_SPARE: u8 = 1 << 0;
+pub const NVME_SMART_CRIT_TEMPERATURE: u8 = 1 << 1;
+pub const NVME_SMART_CRIT_RELIABILITY: u8 = 1 << 2;
+pub const NVME_SMART_CRIT_MEDIA: u8 = 1 << 3;
+pub const NVME_SMART_CRIT_VOLATILE_MEMORY: u8 = 1 << 4;
+pub const NVME_SMART_CRIT_PMR_RO: u8 = 1 << 5;
+
+// smart / health information log page (lid 0x02)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeSmartLog {
+    pub critical_warning: u8,
+    // kelvin
+    pub composite_temperature: u16,
+    // percent
+    pub avail_spare: u8,
+    pub spare_thresh: u8,
+    // may exceed 100
+    pub percent_used: u8,
+    // in thousands of 512 byte units
+    pub data_units_read: u128,
+    pub data_units_written: u128,
+    pub host_reads: u128,
+    pub host_writes: u128,
+    // minutes
+    pub ctrl_busy_time: u128,
+    pub power_cycles: u128,
+    pub power_on_hours: u128,
+    pub unsafe_shutdowns: u128,
+    pub media_errors: u128,
+    pub num_err_log_entries: u128,
+    // minutes
+    pub warning_temp_time: u32,
+    pub critical_comp_time: u32,
+    // kelvin, 0 = not implemented
+    pub temp_sensor: [u16; 8],
+}
+
+impl NvmeSmartLog {
+    pub fn parse(data: &[u8]) -> Self {
+        let mut temp_sensor = [0u16; 8];
+        for (i, t) in temp_sensor.iter_mut().enumerate() {
+            *t = le16(data, 200 + i * 2);
+        }
+
+        Self {
+            critical_warning: data[0],
+            composite_temperature: le16(data, 1),
+            avail_spare: data[3],
+            spare_thresh: data[4],
+            percent_used: data[5],
+            data_units_read: le128(data, 32),
+            data_units_written: le128(data, 48),
+            host_reads: le128(data, 64),
+            host_writes: le128(data, 80),
+            ctrl_busy_time: le128(data, 96),
+            power_cycles: le128(data, 112),
+            power_on_hours: le128(data, 128),
+            unsafe_shutdowns: le128(data, 144),
+            media_errors: le128(data, 160),
+            num_err_log_entries: le128(data, 176),
+            warning_temp_time: le32(data, 192),
+            critical_comp_time: le32(data, 196),
+            temp_sensor,
+        }
+    }
+
+    pub fn temperature_celsius(&self) -> i32 {
+        self.composite_temperature as i32 - 273
+    }
+}
+
+// error information log entry (lid 0x01), 64 bytes each
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeErrorLogEntry {
+    // unique, incrementing error identifier, 0 = entry is empty
+    pub error_count: u64,
+    pub sqid: u16,
+    // 0xffff = not associated with a command
+    pub cmdid: u16,
+    // same layout as the completion queue entry status field
+    pub status_field: u16,
+    // bit 0-7: byte, bit 8-10: bit of the command that caused the error
+    pub param_error_location: u16,
+    pub lba: u64,
+    pub nsid: u32,
+    pub vs: u8,
+    pub trtype: u8,
+    pub cs: u64,
+    pub trtype_spec_info: u16,
+}
+
+impl NvmeErrorLogEntry {
+    pub const SIZE: usize = 64;
+
+    pub fn parse(data: &[u8]) -> Self {
+        Self {
+            error_count: le64(data, 0),
+            sqid: le16(data, 8),
+            cmdid: le16(data, 10),
+            status_field: le16(data, 12),
+            param_error_location: le16(data, 14),
+            lba: le64(data, 16),
+            nsid: le32(data, 24),
+            vs: data[28],
+            trtype: data[29],
+            cs: le64(data, 32),
+            trtype_spec_info: le16(data, 40),
+        }
+    }
+}
+
+pub const NVME_ADMIN_ASYNC_EVENT: u8 = 0x0c;
+
+// asynchronous event configuration feature
+// bit 0-7: smart / health critical warnings, bit 8-31: notices, same layout as OAES
+pub const NVME_AEN_CFG_SMART_MASK: u32 = 0xff;
+pub const NVME_AEN_CFG_NOTICE_MASK: u32 = 0xffffff00;
+pub const NVME_AEN_CFG_NS_ATTR: u32 = 1 << 8;
+pub const NVME_AEN_CFG_FW_ACT: u32 = 1 << 9;
+
+// asynchronous event type
+pub const NVME_AER_TYPE_ERROR: u8 = 0;
+pub const NVME_AER_TYPE_SMART: u8 = 1;
+pub const NVME_AER_TYPE_NOTICE: u8 = 2;
+pub const NVME_AER_TYPE_IO_CMD: u8 = 6;
+pub const NVME_AER_TYPE_VENDOR: u8 = 7;
+
+// asynchronous event information, smart / health status
+pub const NVME_AER_SMART_RELIABILITY: u8 = 0x00;
+pub const NVME_AER_SMART_TEMP_THRESH: u8 = 0x01;
+pub const NVME_AER_SMART_SPARE_THRESH: u8 = 0x02;
+
+// asynchronous event information, notice
+pub const NVME_AER_NOTICE_NS_CHANGED: u8 = 0x00;
+pub const NVME_AER_NOTICE_FW_ACT_STARTING: u8 = 0x01;
+pub const NVME_AER_NOTICE_TELEMETRY: u8 = 0x02;
+pub const NVME_AER_NOTICE_ANA: u8 = 0x03;
+
+// asynchronous event decoded from dword0 of the completion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NvmeAsyncEvent {
+    pub event_type: u8,
+    pub info: u8,
+    // log page to read for details, it is read by the driver before delivery
+    pub lid: u8,
+}
+
+impl NvmeAsyncEvent {
+    pub fn from_result(result: u32) -> Self {
+        Self {
+            event_type: (result & 0x7) as u8,
+            info: (result >> 8) as u8,
+            lid: (result >> 16) as u8,
+        }
+    }
+}
+
+// how a namespace changed after a namespace attribute changed notice or a rescan
+#[derive(Debug, Clone, Copy)]
+pub enum NvmeNsChange {
+    // newly attached, or not known to the driver before
+    Added(NvmeIdNs),
+    Removed,
+    // capacity or lba size changed
+    Resized(NvmeIdNs),
+}
+
+impl NvmeNsChange {
+    // change between two identify results of a namespace, None when inactive
+    pub fn between(old: Option<&NvmeIdNs>, new: Option<&NvmeIdNs>) -> Option<Self> {
+        match (old, new) {
+            (None, Some(id_ns)) => Some(Self::Added(*id_ns)),
+            (Some(_), None) => Some(Self::Removed),
+            (Some(old), Some(id_ns))
+                if old.nsze != id_ns.nsze || old.lba_size() != id_ns.lba_size() =>
+            {
+                Some(Self::Resized(*id_ns))
+            }
+            _ => None,
+        }
+    }
+}
+
+pub trait NvmeEventHandler: Send {
+    // log holds the log page of the event
+    fn handle_event(&mut self, event: &NvmeAsyncEvent, log: &[u8]);
+
+    fn namespace_changed(&mut self, _nsid: u32, _change: NvmeNsChange) {}
+}
+
+// changed namespace list log page (lid 0x04)
+#[derive(Debug, Clone, Default)]
+pub struct NvmeChangedNsList {
+    pub nsids: Vec<u32>,
+    // more than 1024 namespaces changed, every namespace has to be rescanned
+    pub overflow: bool,
+}
+
+impl NvmeChangedNsList {
+    pub fn parse(data: &[u8]) -> Self {
+        let mut list = Self::default();
+        for entry in data.chunks_exact(4).take(1024) {
+            let nsid = le32(entry, 0);
+            if nsid == 0 {
+                break;
+            }
+            if nsid == NVME_NSID_ALL {
+                list.overflow = true;
+                list.nsids.clear();
+                break;
+            }
+            list.nsids.push(nsid);
+        }
+        list
+    }
+}
+
+pub const NVME_ADMIN_SET_FEATURES: u8 = 0x09;
+pub const NVME_ADMIN_GET_FEATURES: u8 = 0x0a;
+
+// identify controller oncs field
+pub const NVME_CTRL_ONCS_SAVE_SELECT: u16 = 1 << 4;
+
+// set features dword10 save
+pub const NVME_FEAT_SAVE: u32 = 1 << 31;
+
+// capabilities returned by get features with NvmeFeatureSel::Supported
+pub const NVME_FEAT_CAP_SAVE: u32 = 1 << 0;
+pub const NVME_FEAT_CAP_NS_SPECIFIC: u32 = 1 << 1;
+pub const NVME_FEAT_CAP_CHANGEABLE: u32 = 1 << 2;
+
+// get features select field (SEL)
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeFeatureSel {
+    Current = 0,
+    Default = 1,
+    Saved = 2,
+    Supported = 3,
+}
+
+// arbitration feature (fid 0x01)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NvmeArbitration {
+    // arbitration burst, 2^n commands, 7 = no limit
+    pub ab: u8,
+    // low / medium / high priority weight, 0's based
+    pub lpw: u8,
+    pub mpw: u8,
+    pub hpw: u8,
+}
+
+impl NvmeArbitration {
+    pub fn from_dword(dword: u32) -> Self {
+        Self {
+            ab: (dword & 0x7) as u8,
+            lpw: (dword >> 8) as u8,
+            mpw: (dword >> 16) as u8,
+            hpw: (dword >> 24) as u8,
+        }
+    }
+
+    pub fn to_dword(&self) -> u32 {
+        (self.ab & 0x7) as u32
+            | (self.lpw as u32) << 8
+            | (self.mpw as u32) << 16
+            | (self.hpw as u32) << 24
+    }
+}
+
+// power management feature (fid 0x02)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NvmePowerMgmt {
+    // power state
+    pub ps: u8,
+    // workload hint
+    pub wh: u8,
+}
+
+impl NvmePowerMgmt {
+    pub fn from_dword(dword: u32) -> Self {
+        Self {
+            ps: (dword & 0x1f) as u8,
+            wh: ((dword >> 5) & 0x7) as u8,
+        }
+    }
+
+    pub fn to_dword(&self) -> u32 {
+        (self.ps & 0x1f) as u32 | ((self.wh & 0x7) as u32) << 5
+    }
+}
+
+// lba range type entry (fid 0x03), 64 bytes each
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NvmeLbaRangeType {
+    // 0 general purpose, 1 filesystem, 2 raid, 3 cache, 4 page / swap file
+    pub range_type: u8,
+    // bit 0: may be overwritten, bit 1: hidden from the os
+    pub attributes: u8,
+    pub slba: u64,
+    // number of logical blocks, 0's based
+    pub nlb: u64,
+    pub guid: [u8; 16],
+}
+
+impl NvmeLbaRangeType {
+    pub const SIZE: usize = 64;
+
+    pub fn parse(data: &[u8]) -> Self {
+        let mut guid = [0u8; 16];
+        guid.copy_from_slice(&data[32..48]);
+        Self {
+            range_type: data[0],
+            attributes: data[1],
+            slba: le64(data, 16),
+            nlb: le64(data, 24),
+            guid,
+        }
+    }
+
+    pub fn write_to(&self, data: &mut [u8]) {
+        data[..Self::SIZE].fill(0);
+        data[0] = self.range_type;
+        data[1] = self.attributes;
+        data[16..24].copy_from_slice(&self.slba.to_le_bytes());
+        data[24..32].copy_from_slice(&self.nlb.to_le_bytes());
+        data[32..48].copy_from_slice(&self.guid);
+    }
+}
+
+// error recovery feature (fid 0x05)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NvmeErrorRecovery {
+    // time limited error recovery, 100ms units, 0 = no limit
+    pub tler: u16,
+    // deallocated or unwritten logical block error enable
+    pub dulbe: bool,
+}
+
+impl NvmeErrorRecovery {
+    pub fn from_dword(dword: u32) -> Self {
+        Self {
+            tler: dword as u16,
+            dulbe: dword & (1 << 16) != 0,
+        }
+    }
+
+    pub fn to_dword(&self) -> u32 {
+        self.tler as u32 | (self.dulbe as u32) << 16
+    }
+}
+
+// interrupt coalescing feature (fid 0x08)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NvmeIrqCoalesce {
+    // aggregation threshold, 0's based
+    pub thr: u8,
+    // aggregation time, 100us units
+    pub time: u8,
+}
+
+impl NvmeIrqCoalesce {
+    pub fn from_dword(dword: u32) -> Self {
+        Self {
+            thr: dword as u8,
+            time: (dword >> 8) as u8,
+        }
+    }
+
+    pub fn to_dword(&self) -> u32 {
+        self.thr as u32 | (self.time as u32) << 8
+    }
+}
+
+// timestamp feature (fid 0x0e)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NvmeTimestamp {
+    // milliseconds since midnight 01-Jan-1970 UTC, 48 bits
+    pub timestamp_ms: u64,
+    // the controller may have stopped counting, e.g. in a non-operational power state
+    pub synch: bool,
+    // 0 reset, 1 set by set features
+    pub origin: u8,
+}
+
+impl NvmeTimestamp {
+    pub fn parse(data: &[u8]) -> Self {
+        Self {
+            timestamp_ms: le64(data, 0) & 0xffff_ffff_ffff,
+            synch: data[6] & 0x1 != 0,
+            origin: (data[6] >> 1) & 0x7,
+        }
+    }
+}
+
+// host behavior support feature (fid 0x16)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NvmeHostBehavior {
+    // advanced command retry enable
+    pub acre: bool,
+    // extended telemetry data area 4 supported
+    pub etdas: bool,
+    // lba format extension enable
+    pub lbafee: bool,
+}
+
+impl NvmeHostBehavior {
+    pub fn parse(data: &[u8]) -> Self {
+        Self {
+            acre: data[0] & 0x1 != 0,
+            etdas: data[1] & 0x1 != 0,
+            lbafee: data[2] & 0x1 != 0,
+        }
+    }
+
+    pub fn write_to(&self, data: &mut [u8]) {
+        data[..512].fill(0);
+        data[0] = self.acre as u8;
+        data[1] = self.etdas as u8;
+        data[2] = self.lbafee as u8;
+    }
+}
+
+// autonomous power state transition feature (fid 0x0c), 32 entries of 8 bytes, one per power state
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NvmeApstTable {
+    // bit 7:3 idle transition power state, bit 31:8 idle time prior to transition in ms
+    pub entries: [u64; 32],
+}
+
+// idle time to wait before a transition, as a multiple of the total entry + exit latency
+pub const NVME_APST_IDLE_FACTOR: u64 = 50;
+const NVME_APST_MAX_IDLE_MS: u64 = (1 << 24) - 1;
+
+impl NvmeApstTable {
+    pub const SIZE: usize = 256;
+
+    pub fn parse(data: &[u8]) -> Self {
+        let mut entries = [0u64; 32];
+        for (i, entry) in entries.iter_mut().enumerate() {
+            *entry = le64(data, i * 8);
+        }
+        Self { entries }
+    }
+
+    // an entry of 0 means no autonomous transition out of that power state
+    pub fn set_transition(&mut self, from_ps: u8, to_ps: u8, idle_ms: u32) {
+        let idle_ms = (idle_ms as u64).min(NVME_APST_MAX_IDLE_MS);
+        self.entries[from_ps as usize] = ((to_ps as u64 & 0x1f) << 3) | (idle_ms << 8);
+    }
+
+    // (idle transition power state, idle time in ms) of the power state, None if disabled
+    pub fn transition(&self, ps: u8) -> Option<(u8, u32)> {
+        let entry = self.entries[ps as usize];
+        if entry == 0 {
+            return None;
+        }
+        Some((((entry >> 3) & 0x1f) as u8, ((entry >> 8) & NVME_APST_MAX_IDLE_MS) as u32))
+    }
+
+    // walk the power states from the deepest one, every state above a usable non-operational
+    // state transitions to it after NVME_APST_IDLE_FACTOR times its total latency.
+    // non-operational states with an exit latency above max_exit_lat_us are skipped
+    pub fn from_power_states(states: &[NvmePowerState], max_exit_lat_us: u32) -> Self {
+        let mut table = Self::default();
+        let mut target = 0u64;
+        for ps in (0..states.len()).rev() {
+            if target != 0 {
+                table.entries[ps] = target;
+            }
+
+            let state = &states[ps];
+            if !state.non_operational() || state.exit_lat > max_exit_lat_us {
+                continue;
+            }
+
+            // round up to ms
+            let total_lat_ms = (state.entry_lat as u64 + state.exit_lat as u64 + 999) / 1000;
+            let idle_ms = (total_lat_ms * NVME_APST_IDLE_FACTOR).min(NVME_APST_MAX_IDLE_MS);
+            target = ((ps as u64 & 0x1f) << 3) | (idle_ms << 8);
+        }
+        table
+    }
+
+    pub fn write_to(&self, data: &mut [u8]) {
+        for (i, entry) in self.entries.iter().enumerate() {
+            data[i * 8..i * 8 + 8].copy_from_slice(&entry.to_le_bytes());
+        }
+    }
+}
+
+// zoned namespace command set opcode
+pub const NVME_CMD_ZONE_MGMT_SEND: u8 = 0x79;
+pub const NVME_CMD_ZONE_MGMT_RECV: u8 = 0x7a;
+pub const NVME_CMD_ZONE_APPEND: u8 = 0x7d;
+
+// zone management send dword13
+pub const NVME_ZONE_SEND_SELECT_ALL: u32 = 1 << 8;
+// zone management receive dword13
+pub const NVME_ZONE_RECV_REPORT: u32 = 0x00;
+pub const NVME_ZONE_RECV_PARTIAL: u32 = 1 << 16;
+
+// zone report header and zone descriptor size
+pub const NVME_ZONE_REPORT_HDR_SIZE: usize = 64;
+pub const NVME_ZONE_DESC_SIZE: usize = 64;
+
+// zone type, the only one defined
+pub const NVME_ZONE_TYPE_SEQWRITE_REQ: u8 = 0x2;
+
+// zone send action
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeZoneAction {
+    Close = 0x1,
+    Finish = 0x2,
+    Open = 0x3,
+    Reset = 0x4,
+    Offline = 0x5,
+}
+
+// zone receive action specific filter, the states a report includes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeZoneFilter {
+    All = 0x0,
+    Empty = 0x1,
+    ImplicitOpen = 0x2,
+    ExplicitOpen = 0x3,
+    Closed = 0x4,
+    Full = 0x5,
+    ReadOnly = 0x6,
+    Offline = 0x7,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeZoneState {
+    Empty,
+    ImplicitOpen,
+    ExplicitOpen,
+    Closed,
+    ReadOnly,
+    Full,
+    Offline,
+    Unknown(u8),
+}
+
+impl NvmeZoneState {
+    pub fn from_raw(zs: u8) -> Self {
+        match zs {
+            0x1 => Self::Empty,
+            0x2 => Self::ImplicitOpen,
+            0x3 => Self::ExplicitOpen,
+            0x4 => Self::Closed,
+            0xd => Self::ReadOnly,
+            0xe => Self::Full,
+            0xf => Self::Offline,
+            _ => Self::Unknown(zs),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NvmeZoneDescriptor {
+    pub zone_type: u8,
+    pub state: NvmeZoneState,
+    // zone attributes
+    pub za: u8,
+    // zone capacity, in logical blocks
+    pub zcap: u64,
+    // zone start lba
+    pub zslba: u64,
+    // write pointer
+    pub wp: u64,
+}
+
+impl NvmeZoneDescriptor {
+    pub fn parse(data: &[u8]) -> Self {
+        Self {
+            zone_type: data[0] & 0xf,
+            state: NvmeZoneState::from_raw(data[1] >> 4),
+            za: data[2],
+            zcap: le64(data, 8),
+            zslba: le64(data, 16),
+            wp: le64(data, 24),
+        }
+    }
+}
+
+// identify controller data of the zoned namespace command set (cns 0x06, csi 0x02)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeZnsIdCtrl {
+    // zone append size limit, 2^n units of CAP.MPSMIN, 0 means MDTS
+    pub zasl: u8,
+}
+
+impl NvmeZnsIdCtrl {
+    pub fn parse(data: &[u8]) -> Self {
+        Self { zasl: data[0] }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeZnsLbafe {
+    // zone size, in logical blocks
+    pub zsze: u64,
+    // zone descriptor extension size, in 64 bytes
+    pub zdes: u8,
+}
+
+// identify namespace data of the zoned namespace command set (cns 0x05, csi 0x02)
+#[derive(Debug, Clone, Copy)]
+pub struct NvmeZnsIdNs {
+    // zone operation characteristics
+    pub zoc: u16,
+    // optional zoned command support
+    pub ozcs: u16,
+    // maximum active and open resources, 0's based, 0xffffffff for no limit
+    pub mar: u32,
+    pub mor: u32,
+    // reset and finish recommended limits, in seconds
+    pub rrl: u32,
+    pub frl: u32,
+    pub lbafe: [NvmeZnsLbafe; NVME_NS_LBAF_MAX],
+}
+
+impl Default for NvmeZnsIdNs {
+    fn default() -> Self {
+        Self::parse(&[0; 2816 + NVME_NS_LBAF_MAX * 16])
+    }
+}
+
+impl NvmeZnsIdNs {
+    pub fn parse(data: &[u8]) -> Self {
+        let mut lbafe = [NvmeZnsLbafe::default(); NVME_NS_LBAF_MAX];
+        for (i, f) in lbafe.iter_mut().enumerate() {
+            let offset = 2816 + i * 16;
+            f.zsze = le64(data, offset);
+            f.zdes = data[offset + 8];
+        }
+
+        Self {
+            zoc: le16(data, 0),
+            ozcs: le16(data, 2),
+            mar: le32(data, 4),
+            mor: le32(data, 8),
+            rrl: le32(data, 12),
+            frl: le32(data, 16),
+            lbafe,
+        }
+    }
+
+    // zone size of the lba format in use by id_ns
+    pub fn zone_size(&self, id_ns: &NvmeIdNs) -> u64 {
+        self.lbafe[id_ns.lba_format_index()].zsze
+    }
+}
+
+// key value command set opcode
+pub const NVME_CMD_KV_STORE: u8 = 0x01;
+pub const NVME_CMD_KV_RETRIEVE: u8 = 0x02;
+pub const NVME_CMD_KV_LIST: u8 = 0x06;
+pub const NVME_CMD_KV_DELETE: u8 = 0x10;
+pub const NVME_CMD_KV_EXIST: u8 = 0x14;
+
+// store option, only overwrite an existing key or never overwrite one
+pub const NVME_KV_STORE_MUST_EXIST: u8 = 1 << 0;
+pub const NVME_KV_STORE_NO_OVERWRITE: u8 = 1 << 1;
+
+// longest key the driver passes in a command
+pub const NVME_KV_MAX_KEY_LEN: usize = 16;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NvmeKvCommand {
+    pub opcode: u8,
+    pub flags: u8,
+    pub command_id: u16,
+    pub nsid: u32,
+    // key bytes 8-15
+    pub key_hi: u64,
+    pub rsvd4: u64,
+    pub prp1: u64,
+    pub prp2: u64,
+    // value size of store, host buffer size of retrieve and list
+    pub size: u32,
+    // key length
+    pub kl: u8,
+    // store or retrieve option
+    pub option: u8,
+    pub rsvd11: u16,
+    pub rsvd12: [u32; 2],
+    // key bytes 0-7
+    pub key_lo: u64,
+}
+
+impl NvmeKvCommand {
+    pub fn new(opcode: u8, nsid: u32, key: &[u8]) -> Self {
+        let mut padded = [0u8; NVME_KV_MAX_KEY_LEN];
+        padded[..key.len()].copy_from_slice(key);
+        Self {
+            opcode,
+            nsid,
+            key_lo: le64(&padded, 0),
+            key_hi: le64(&padded, 8),
+            kl: key.len() as u8,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeKvFormat {
+    // maximum key length
+    pub kml: u16,
+    // maximum value length
+    pub vml: u32,
+    // maximum number of keys, 0 for no limit
+    pub mnk: u32,
+}
+
+// identify namespace data of the key value command set (cns 0x05, csi 0x01)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeKvIdNs {
+    // namespace size and utilization, in bytes
+    pub nsze: u64,
+    pub nuse: u64,
+    // number of kv formats, 0's based
+    pub nkvf: u8,
+    pub kvf: [NvmeKvFormat; 16],
+}
+
+impl NvmeKvIdNs {
+    pub fn parse(data: &[u8]) -> Self {
+        let mut kvf = [NvmeKvFormat::default(); 16];
+        for (i, f) in kvf.iter_mut().enumerate() {
+            let offset = 72 + i * 16;
+            f.kml = le16(data, offset);
+            f.vml = le32(data, offset + 4);
+            f.mnk = le32(data, offset + 8);
+        }
+
+        Self {
+            nsze: le64(data, 0),
+            nuse: le64(data, 16),
+            nkvf: data[25],
+            kvf,
+        }
+    }
+
+    // None when the namespace does not report kv format index
+    pub fn kv_format(&self, index: u8) -> Option<NvmeKvFormat> {
+        if index > self.nkvf {
+            return None;
+        }
+        self.kvf.get(index as usize).copied()
+    }
+
+    // identify lists the kv formats but not the index of the one in use. with a single
+    // format that is the one, otherwise only the limits every format allows are safe
+    pub fn format_in_use(&self) -> NvmeKvFormat {
+        if self.nkvf == 0 {
+            self.kvf[0]
+        } else {
+            self.common_kv_format()
+        }
+    }
+
+    // limits allowed by every reported kv format, for a namespace whose format is unknown
+    pub fn common_kv_format(&self) -> NvmeKvFormat {
+        let count = (self.nkvf as usize + 1).min(self.kvf.len());
+        let mut common = self.kvf[0];
+        for f in &self.kvf[1..count] {
+            common.kml = common.kml.min(f.kml);
+            common.vml = common.vml.min(f.vml);
+            // 0 is no limit
+            common.mnk = match (common.mnk, f.mnk) {
+                (0, mnk) | (mnk, 0) => mnk,
+                (a, b) => a.min(b),
+            };
+        }
+        common
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::size_of;
+    use alloc::vec;
+    use crate::nvme::nvme_error::*;
+
+    #[test]
+    fn source_range_layout() {
+        assert_eq!(size_of::<NvmeSourceRange>(), 32);
+        let range = NvmeSourceRange::new(0x1122_3344_5566_7788, 7);
+        let raw: [u8; 32] = unsafe { core::mem::transmute(range) };
+        assert_eq!(raw[8..16], 0x1122_3344_5566_7788u64.to_le_bytes());
+        assert_eq!(raw[16..18], 7u16.to_le_bytes());
+    }
+
+    // 4 source ranges of at most 16 blocks, 32 blocks in total
+    fn copy_ns() -> NvmeIdNs {
+        NvmeIdNs { msrc: 3, mssrl: 16, mcl: 32, ..Default::default() }
+    }
+
+    #[test]
+    fn copy_within_limits() {
+        let id_ns = copy_ns();
+        // nlb is 0's based
+        assert!(id_ns.copy_within_limits(&[NvmeSourceRange::new(0, 15)]));
+        let ranges = [NvmeSourceRange::new(0, 15), NvmeSourceRange::new(64, 15)];
+        assert!(id_ns.copy_within_limits(&ranges));
+        assert!(id_ns.copy_within_limits(&[NvmeSourceRange::new(0, 0); 4]));
+    }
+
+    #[test]
+    fn copy_outside_limits() {
+        let id_ns = copy_ns();
+        // range longer than mssrl
+        assert!(!id_ns.copy_within_limits(&[NvmeSourceRange::new(0, 16)]));
+        // more ranges than msrc
+        assert!(!id_ns.copy_within_limits(&[NvmeSourceRange::new(0, 0); 5]));
+        // more blocks than mcl
+        let ranges = [
+            NvmeSourceRange::new(0, 15),
+            NvmeSourceRange::new(64, 15),
+            NvmeSourceRange::new(128, 0),
+        ];
+        assert!(!id_ns.copy_within_limits(&ranges));
+    }
+
+    #[test]
+    fn id_ns_parse() {
+        let mut data = [0u8; 4096];
+        data[0..8].copy_from_slice(&0x10_0000u64.to_le_bytes());
+        data[8..16].copy_from_slice(&0x8_0000u64.to_le_bytes());
+        data[26] = 1;
+        data[74..76].copy_from_slice(&128u16.to_le_bytes());
+        data[76..80].copy_from_slice(&1024u32.to_le_bytes());
+        data[80] = 7;
+        // lba format 0: 512 bytes, lba format 1: 4KiB with 8 bytes of metadata
+        data[130] = 9;
+        data[132..134].copy_from_slice(&8u16.to_le_bytes());
+        data[134] = 12;
+
+        let id_ns = NvmeIdNs::parse(&data);
+        assert_eq!((id_ns.nsze, id_ns.ncap), (0x10_0000, 0x8_0000));
+        assert_eq!((id_ns.mssrl, id_ns.mcl, id_ns.msrc), (128, 1024, 7));
+        assert_eq!((id_ns.lba_format().ms, id_ns.lba_format().lbads), (8, 12));
+        assert_eq!(id_ns.lba_size(), 4096);
+    }
+
+    // reservation status header with regctl registered controllers
+    fn resv_header(size: usize, rtype: u8, regctl: u16) -> Vec<u8> {
+        let mut data = vec![0u8; size];
+        data[0..4].copy_from_slice(&7u32.to_le_bytes());
+        data[4] = rtype;
+        data[5..7].copy_from_slice(&regctl.to_le_bytes());
+        data[9] = 1;
+        data
+    }
+
+    #[test]
+    fn reservation_status_parse() {
+        let mut data = resv_header(24 + 2 * 24, 2, 2);
+        for i in 0..2 {
+            let entry = &mut data[24 + i * 24..];
+            entry[0..2].copy_from_slice(&(i as u16 + 1).to_le_bytes());
+            entry[2] = (i == 0) as u8;
+            entry[8..16].copy_from_slice(&(0x1000 + i as u64).to_le_bytes());
+            entry[16..24].copy_from_slice(&(0xabcd + i as u64).to_le_bytes());
+        }
+
+        let status = NvmeReservationStatus::parse(&data, false);
+        assert_eq!(status.gen, 7);
+        assert_eq!(status.rtype, Some(NvmeReservationType::ExclusiveAccess));
+        assert_eq!(status.ptpls, 1);
+        assert_eq!(status.regctl.len(), 2);
+        for (i, ctrl) in status.regctl.iter().enumerate() {
+            assert_eq!(ctrl.cntlid, i as u16 + 1);
+            assert_eq!(ctrl.rcsts, (i == 0) as u8);
+            assert_eq!(ctrl.hostid[..8], (0x1000 + i as u64).to_le_bytes());
+            assert_eq!(ctrl.hostid[8..], [0; 8]);
+            assert_eq!(ctrl.rkey, 0xabcd + i as u64);
+        }
+    }
+
+    #[test]
+    fn reservation_status_parse_extended() {
+        let mut data = resv_header(64 + 64, 0, 1);
+        let entry = &mut data[64..];
+        entry[0..2].copy_from_slice(&3u16.to_le_bytes());
+        entry[2] = 1;
+        entry[8..16].copy_from_slice(&0x1234u64.to_le_bytes());
+        entry[16..32].copy_from_slice(&[0x5a; 16]);
+
+        let status = NvmeReservationStatus::parse(&data, true);
+        // no reservation held
+        assert_eq!(status.rtype, None);
+        assert_eq!(status.regctl.len(), 1);
+        let ctrl = status.regctl[0];
+        assert_eq!((ctrl.cntlid, ctrl.rcsts, ctrl.rkey), (3, 1, 0x1234));
+        assert_eq!(ctrl.hostid, [0x5a; 16]);
+    }
+
+    #[test]
+    fn reservation_status_parse_truncated() {
+        // more registered controllers than the report buffer holds
+        let data = resv_header(24 + 2 * 24, 1, 5);
+        assert_eq!(NvmeReservationStatus::parse(&data, false).regctl.len(), 2);
+    }
+
+    #[test]
+    fn id_ns_lba_format_above_15() {
+        let mut data = [0u8; 4096];
+        // format 37: flbas bits 3:0 are 5, bits 6:5 are 2, metadata stays at the end
+        data[26] = 0x5 | 2 << 5 | NVME_NS_FLBAS_META_EXT;
+        data[128 + 37 * 4 + 2] = 12;
+        let id_ns = NvmeIdNs::parse(&data);
+        assert_eq!(id_ns.lba_format_index(), 37);
+        assert_eq!(id_ns.lba_size(), 4096);
+        assert!(id_ns.extended_lba());
+
+        data[2816 + 37 * 16] = 0x80;
+        assert_eq!(NvmeZnsIdNs::parse(&data).zone_size(&id_ns), 0x80);
+    }
+
+    #[test]
+    fn ns_create_data_round_trip() {
+        let id_ns = NvmeIdNs {
+            nsze: 0x2_0000,
+            ncap: 0x1_0000,
+            flbas: 1,
+            dps: 1,
+            nmic: 1,
+            ..Default::default()
+        };
+        let mut data = [0xffu8; 4096];
+        id_ns.write_create_data(&mut data);
+
+        let parsed = NvmeIdNs::parse(&data);
+        assert_eq!((parsed.nsze, parsed.ncap, parsed.nuse), (0x2_0000, 0x1_0000, 0));
+        assert_eq!((parsed.flbas, parsed.dps, parsed.nmic), (1, 1, 1));
+        // fields the host does not supply are cleared
+        assert_eq!(parsed.nsfeat, 0);
+        assert!(data[384..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn format_cdw10() {
+        assert_eq!(nvme_format_cdw10(0, NvmeSecureErase::None, 0, false, false), 0);
+        assert_eq!(
+            nvme_format_cdw10(3, NvmeSecureErase::UserData, 1, false, true),
+            3 | NVME_FORMAT_MSET | 1 << 5 | 1 << 9
+        );
+        assert_eq!(
+            nvme_format_cdw10(2, NvmeSecureErase::Crypto, 2, true, false),
+            2 | 2 << 5 | NVME_FORMAT_PIL | 2 << 9
+        );
+        // lba format index 0x15: low bits 3:0, high bits 13:12
+        assert_eq!(nvme_format_cdw10(0x15, NvmeSecureErase::None, 0, false, false), 0x5 | 1 << 12);
+    }
+
+    fn sanitize_log(sprog: u16, sstat: u16) -> NvmeSanitizeLog {
+        let mut data = [0u8; 512];
+        data[0..2].copy_from_slice(&sprog.to_le_bytes());
+        data[2..4].copy_from_slice(&sstat.to_le_bytes());
+        data[4..8].copy_from_slice(&0x2u32.to_le_bytes());
+        data[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        NvmeSanitizeLog::parse(&data)
+    }
+
+    #[test]
+    fn sanitize_log_in_progress() {
+        let log = sanitize_log(0x8000, 0x2 | 3 << 3);
+        assert_eq!(log.status(), NvmeSanitizeStatus::InProgress);
+        assert_eq!(log.progress_percent(), 50);
+        assert_eq!(log.overwrite_passes(), 3);
+        assert!(!log.global_data_erased());
+        assert_eq!((log.scdw10, log.eto), (0x2, u32::MAX));
+    }
+
+    #[test]
+    fn sanitize_log_status() {
+        // progress is only meaningful while the operation runs
+        let log = sanitize_log(0x1234, 0x1 | 1 << 8);
+        assert_eq!(log.status(), NvmeSanitizeStatus::Completed);
+        assert_eq!(log.progress_percent(), 100);
+        assert!(log.global_data_erased());
+
+        assert_eq!(sanitize_log(0, 0).status(), NvmeSanitizeStatus::NeverSanitized);
+        assert_eq!(sanitize_log(0, 0x3).status(), NvmeSanitizeStatus::Failed);
+        assert_eq!(sanitize_log(0, 0x4).status(), NvmeSanitizeStatus::CompletedNoDealloc);
+        assert_eq!(sanitize_log(0, 0x7).status(), NvmeSanitizeStatus::Unknown(7));
+    }
+
+    #[test]
+    fn fw_slot_log_parse() {
+        let mut data = [0u8; 512];
+        // running from slot 2, slot 3 activated at the next reset
+        data[0] = 3 << 4 | 2;
+        data[16..24].copy_from_slice(b"1.0.2   ");
+        data[24..32].copy_from_slice(b"1.1.0   ");
+
+        let log = NvmeFwSlotLog::parse(&data);
+        assert_eq!(log.active_slot(), 2);
+        assert_eq!(log.next_slot(), Some(3));
+        assert_eq!(log.revision(1), [0; 8]);
+        assert_eq!(log.revision(2), b"1.0.2   ");
+        assert_eq!(log.revision(3), b"1.1.0   ");
+
+        data[0] = 1;
+        assert_eq!(NvmeFwSlotLog::parse(&data).next_slot(), None);
+    }
+
+    #[test]
+    fn smart_log_parse() {
+        let mut data = [0u8; 512];
+        data[0] = NVME_SMART_CRIT_SPARE;
+        data[1..3].copy_from_slice(&310u16.to_le_bytes());
+        data[3] = 90;
+        data[4] = 10;
+        data[5] = 3;
+        // counters are 128 bits wide
+        data[32..48].copy_from_slice(&(1u128 << 64 | 5).to_le_bytes());
+        data[48..64].copy_from_slice(&6u128.to_le_bytes());
+        data[128..144].copy_from_slice(&1000u128.to_le_bytes());
+        data[176..192].copy_from_slice(&2u128.to_le_bytes());
+        data[192..196].copy_from_slice(&11u32.to_le_bytes());
+        data[196..200].copy_from_slice(&12u32.to_le_bytes());
+        data[200..202].copy_from_slice(&300u16.to_le_bytes());
+        data[214..216].copy_from_slice(&305u16.to_le_bytes());
+
+        let log = NvmeSmartLog::parse(&data);
+        assert_eq!(log.critical_warning, NVME_SMART_CRIT_SPARE);
+        assert_eq!(log.composite_temperature, 310);
+        assert_eq!(log.temperature_celsius(), 37);
+        assert_eq!((log.avail_spare, log.spare_thresh, log.percent_used), (90, 10, 3));
+        assert_eq!(log.data_units_read, 1 << 64 | 5);
+        assert_eq!(log.data_units_written, 6);
+        assert_eq!(log.power_on_hours, 1000);
+        assert_eq!(log.num_err_log_entries, 2);
+        assert_eq!((log.warning_temp_time, log.critical_comp_time), (11, 12));
+        assert_eq!(log.temp_sensor, [300, 0, 0, 0, 0, 0, 0, 305]);
+    }
+
+    #[test]
+    fn error_log_entry_parse() {
+        let mut data = [0u8; NvmeErrorLogEntry::SIZE * 2];
+        data[0..8].copy_from_slice(&42u64.to_le_bytes());
+        data[8..10].copy_from_slice(&1u16.to_le_bytes());
+        data[10..12].copy_from_slice(&0x33u16.to_le_bytes());
+        data[12..14].copy_from_slice(&((NVME_SC_INVALID_FIELD as u16) << 1).to_le_bytes());
+        data[14..16].copy_from_slice(&(3u16 << 8 | 40).to_le_bytes());
+        data[16..24].copy_from_slice(&0x1_0000_0000u64.to_le_bytes());
+        data[24..28].copy_from_slice(&1u32.to_le_bytes());
+        data[28] = 0x80;
+        data[29] = 0x1;
+        data[32..40].copy_from_slice(&0xfeedu64.to_le_bytes());
+        data[40..42].copy_from_slice(&0x7u16.to_le_bytes());
+
+        let entry = NvmeErrorLogEntry::parse(&data);
+        assert_eq!(entry.error_count, 42);
+        assert_eq!((entry.sqid, entry.cmdid), (1, 0x33));
+        assert_eq!(
+            NvmeError::from_status(entry.status_field),
+            Err(NvmeError::CommandFailed { sct: 0, sc: NVME_SC_INVALID_FIELD, dnr: false })
+        );
+        assert_eq!(entry.param_error_location, 3 << 8 | 40);
+        assert_eq!((entry.lba, entry.nsid), (0x1_0000_0000, 1));
+        assert_eq!((entry.vs, entry.trtype), (0x80, 0x1));
+        assert_eq!((entry.cs, entry.trtype_spec_info), (0xfeed, 0x7));
+
+        // an empty entry has no error count
+        let empty = NvmeErrorLogEntry::parse(&data[NvmeErrorLogEntry::SIZE..]);
+        assert_eq!(empty.error_count, 0);
+    }
+
+    #[test]
+    fn async_event_from_result() {
+        // namespace attribute changed notice, changed namespace list log
+        let result = 0x0004_0002 | (NVME_AER_NOTICE_NS_CHANGED as u32) << 8;
+        let event = NvmeAsyncEvent::from_result(result);
+        assert_eq!(
+            event,
+            NvmeAsyncEvent {
+                event_type: NVME_AER_TYPE_NOTICE,
+                info: NVME_AER_NOTICE_NS_CHANGED,
+                lid: NVME_LOG_CHANGED_NS,
+            }
+        );
+        // temperature threshold warning, reserved bits ignored
+        let event = NvmeAsyncEvent::from_result(0xff02_01f9);
+        assert_eq!(event.event_type, NVME_AER_TYPE_SMART);
+        assert_eq!(event.info, NVME_AER_SMART_TEMP_THRESH);
+        assert_eq!(event.lid, NVME_LOG_SMART);
+    }
+
+    fn ns(nsze: u64, lbads: u8) -> NvmeIdNs {
+        let mut id_ns = NvmeIdNs {
+            nsze,
+            ncap: nsze,
+            ..Default::default()
+        };
+        id_ns.lbaf[0].lbads = lbads;
+        id_ns
+    }
+
+    #[test]
+    fn ns_change_between() {
+        let old = ns(0x1000, 9);
+        assert!(NvmeNsChange::between(None, None).is_none());
+        assert!(NvmeNsChange::between(Some(&old), Some(&old)).is_none());
+        assert!(matches!(
+            NvmeNsChange::between(None, Some(&old)),
+            Some(NvmeNsChange::Added(id_ns)) if id_ns.nsze == 0x1000
+        ));
+        assert!(matches!(
+            NvmeNsChange::between(Some(&old), None),
+            Some(NvmeNsChange::Removed)
+        ));
+        assert!(matches!(
+            NvmeNsChange::between(Some(&old), Some(&ns(0x2000, 9))),
+            Some(NvmeNsChange::Resized(id_ns)) if id_ns.nsze == 0x2000
+        ));
+        assert!(matches!(
+            NvmeNsChange::between(Some(&old), Some(&ns(0x1000, 12))),
+            Some(NvmeNsChange::Resized(id_ns)) if id_ns.lba_size() == 4096
+        ));
+    }
+
+    #[test]
+    fn changed_ns_list_parse() {
+        let mut data = [0u8; 4096];
+        data[0..4].copy_from_slice(&1u32.to_le_bytes());
+        data[4..8].copy_from_slice(&7u32.to_le_bytes());
+        data[12..16].copy_from_slice(&9u32.to_le_bytes());
+        let list = NvmeChangedNsList::parse(&data);
+        assert_eq!(list.nsids, vec![1, 7]);
+        assert!(!list.overflow);
+
+        data[0..4].copy_from_slice(&NVME_NSID_ALL.to_le_bytes());
+        let list = NvmeChangedNsList::parse(&data);
+        assert!(list.nsids.is_empty());
+        assert!(list.overflow);
+    }
+
+    #[test]
+    fn feature_dwords_round_trip() {
+        let arb = NvmeArbitration { ab: 3, lpw: 1, mpw: 2, hpw: 4 };
+        assert_eq!(arb.to_dword(), 0x0402_0103);
+        assert_eq!(NvmeArbitration::from_dword(arb.to_dword()), arb);
+
+        let pm = NvmePowerMgmt { ps: 2, wh: 1 };
+        assert_eq!(pm.to_dword(), 0x22);
+        assert_eq!(NvmePowerMgmt::from_dword(0xffff_ff00 | pm.to_dword()), pm);
+
+        let er = NvmeErrorRecovery { tler: 50, dulbe: true };
+        assert_eq!(er.to_dword(), 0x1_0032);
+        assert_eq!(NvmeErrorRecovery::from_dword(er.to_dword()), er);
+
+        let ic = NvmeIrqCoalesce { thr: 7, time: 10 };
+        assert_eq!(ic.to_dword(), 0x0a07);
+        assert_eq!(NvmeIrqCoalesce::from_dword(ic.to_dword()), ic);
+    }
+
+    #[test]
+    fn lba_range_type_round_trip() {
+        let range = NvmeLbaRangeType {
+            range_type: 1,
+            attributes: 0x3,
+            slba: 0x800,
+            nlb: 0xfff,
+            guid: [0xab; 16],
+        };
+        let mut data = [0xffu8; NvmeLbaRangeType::SIZE];
+        range.write_to(&mut data);
+        assert_eq!(&data[2..16], &[0u8; 14]);
+        assert_eq!(NvmeLbaRangeType::parse(&data), range);
+    }
+
+    #[test]
+    fn timestamp_parse() {
+        let mut data = [0u8; 8];
+        data.copy_from_slice(&0x00ff_0123_4567_89abu64.to_le_bytes());
+        // synch set, origin set by set features
+        data[6] = 0x3;
+        let ts = NvmeTimestamp::parse(&data);
+        assert_eq!(ts.timestamp_ms, 0x0123_4567_89ab);
+        assert!(ts.synch);
+        assert_eq!(ts.origin, 1);
+    }
+
+    #[test]
+    fn host_behavior_round_trip() {
+        let hb = NvmeHostBehavior { acre: true, etdas: false, lbafee: true };
+        let mut data = [0xffu8; 512];
+        hb.write_to(&mut data);
+        assert_eq!(&data[..4], &[1, 0, 1, 0]);
+        assert_eq!(NvmeHostBehavior::parse(&data), hb);
+    }
+
+    // three operational states, then non-operational states 3 (3ms) and 4 (15ms)
+    fn apst_power_states() -> [NvmePowerState; 5] {
+        let mut states = [NvmePowerState::default(); 5];
+        states[3] = NvmePowerState {
+            flags: NVME_PS_FLAGS_NON_OP_STATE,
+            entry_lat: 1000,
+            exit_lat: 2000,
+            ..Default::default()
+        };
+        states[4] = NvmePowerState {
+            flags: NVME_PS_FLAGS_NON_OP_STATE,
+            entry_lat: 5000,
+            exit_lat: 10000,
+            ..Default::default()
+        };
+        states
+    }
+
+    #[test]
+    fn apst_table_from_power_states() {
+        let table = NvmeApstTable::from_power_states(&apst_power_states(), u32::MAX);
+        for ps in 0..3 {
+            assert_eq!(table.transition(ps), Some((3, 3 * NVME_APST_IDLE_FACTOR as u32)));
+        }
+        assert_eq!(table.transition(3), Some((4, 15 * NVME_APST_IDLE_FACTOR as u32)));
+        assert_eq!(table.transition(4), None);
+    }
+
+    #[test]
+    fn apst_table_skips_slow_states() {
+        let table = NvmeApstTable::from_power_states(&apst_power_states(), 5000);
+        for ps in 0..3 {
+            assert_eq!(table.transition(ps), Some((3, 3 * NVME_APST_IDLE_FACTOR as u32)));
+        }
+        assert_eq!(table.transition(3), None);
+        assert_eq!(table.transition(4), None);
+
+        let table = NvmeApstTable::from_power_states(&apst_power_states(), 1000);
+        assert_eq!(table, NvmeApstTable::default());
+    }
+
+    #[test]
+    fn apst_table_round_trip() {
+        let mut table = NvmeApstTable::default();
+        table.set_transition(0, 2, 100);
+        table.set_transition(1, 2, u32::MAX);
+        let mut data = [0u8; NvmeApstTable::SIZE];
+        table.write_to(&mut data);
+
+        let parsed = NvmeApstTable::parse(&data);
+        assert_eq!(parsed, table);
+        assert_eq!(parsed.transition(0), Some((2, 100)));
+        // idle time is 24 bits wide
+        assert_eq!(parsed.transition(1), Some((2, (1 << 24) - 1)));
+    }
+
+    #[test]
+    fn delete_queue_layout() {
+        assert_eq!(size_of::<NvmeDeleteQueue>(), 64);
+        let cmd = NvmeDeleteQueue::new(NVME_ADMIN_DELETE_SQ, 3);
+        let dwords: [u32; 16] = unsafe { core::mem::transmute(cmd) };
+        assert_eq!(dwords[0] & 0xff, NVME_ADMIN_DELETE_SQ as u32);
+        assert_eq!(dwords[10], 3);
+    }
+
+    #[test]
+    fn queue_priority_sq_flags() {
+        // QPRIO is bits 2:1 of the create io sq flags, PC (bit 0) is left to the caller
+        assert_eq!(NvmeQueuePriority::Urgent.sq_flags(), 0);
+        assert_eq!(NvmeQueuePriority::High.sq_flags(), 0x2);
+        assert_eq!(NvmeQueuePriority::Medium.sq_flags(), 0x4);
+        assert_eq!(NvmeQueuePriority::Low.sq_flags(), 0x6);
+    }
+
+    #[test]
+    fn cmb_info_from_regs() {
+        // bar 2 at 1MiB, 16MiB with 64KiB units, sq and prp list support
+        let cmbsz = 256 << 12 | 1 << 8 | NVME_CMBSZ_SQS | NVME_CMBSZ_LISTS;
+        let info = NvmeCmbInfo::from_regs(16 << 12 | 2, cmbsz);
+        assert_eq!(info.bir, 2);
+        assert_eq!(info.offset, 1 << 20);
+        assert_eq!(info.size, 16 << 20);
+        assert_eq!(info.flags, NVME_CMBSZ_SQS | NVME_CMBSZ_LISTS);
+        assert_eq!(NvmeCmbInfo::from_regs(0, 0).size, 0);
+    }
+
+    #[test]
+    fn pmr_info_from_regs() {
+        // bar 4, read and write data, PMRSTS read barrier, 3 minutes to become ready
+        let pmrcap = 4 << 5 | 1 << 3 | 1 << 4 | 1 << 8 | (NVME_PMRWBM_STS_READ as u32) << 10
+            | 3 << 16;
+        // 2 MiB elasticity buffer, 100 KiB/s sustained writes
+        let info = NvmePmrInfo::from_regs(pmrcap, 2 << 8 | 2, 100 << 8 | 1);
+        assert_eq!(info.bir, 4);
+        assert!(info.rds && info.wds && !info.cmss && !info.rbb);
+        assert_eq!(info.wbm, NVME_PMRWBM_STS_READ);
+        assert_eq!(info.timeout_us, 3 * 60 * 1000 * 1000);
+        assert_eq!(info.ebs, 2 << 20);
+        assert_eq!(info.swt, 100 << 10);
+
+        // 500ms units, reserved size units
+        let info = NvmePmrInfo::from_regs(2 << 16, 1 << 8 | 4, 0);
+        assert_eq!(info.timeout_us, 1000 * 1000);
+        assert_eq!(info.ebs, 0);
+    }
+
+    #[test]
+    fn boot_partition_info_from_reg() {
+        // 1MiB partitions, read succeeded, partition 1 active
+        let info = NvmeBootPartitionInfo::from_reg(1 << 31 | 2 << 24 | 8);
+        assert_eq!(info.size, 1 << 20);
+        assert_eq!(info.brs, NVME_BPINFO_BRS_SUCCESS);
+        assert_eq!(info.active, 1);
+        assert_eq!(NvmeBootPartitionInfo::from_reg(0), NvmeBootPartitionInfo::default());
+    }
+
+    #[test]
+    fn nssr_magic() {
+        assert_eq!(&NVME_NSSR_MAGIC.to_be_bytes(), b"NVMe");
+    }
+
+    #[test]
+    fn crc16_t10dif_check_value() {
+        assert_eq!(nvme_crc16_t10dif(0, b"123456789"), 0xd0db);
+        assert_eq!(nvme_crc16_t10dif(0, &[]), 0);
+    }
+
+    #[test]
+    fn crc16_t10dif_continues() {
+        let crc = nvme_crc16_t10dif(0, b"1234");
+        assert_eq!(nvme_crc16_t10dif(crc, b"56789"), 0xd0db);
+    }
+
+    fn zone_desc(zt: u8, zs: u8, za: u8, zcap: u64, zslba: u64, wp: u64) -> [u8; 64] {
+        let mut data = [0u8; NVME_ZONE_DESC_SIZE];
+        data[0] = zt;
+        data[1] = zs << 4;
+        data[2] = za;
+        data[8..16].copy_from_slice(&zcap.to_le_bytes());
+        data[16..24].copy_from_slice(&zslba.to_le_bytes());
+        data[24..32].copy_from_slice(&wp.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn zone_descriptor_parse() {
+        let data = zone_desc(NVME_ZONE_TYPE_SEQWRITE_REQ, 0x2, 0x80, 0x8000, 0x10000, 0x10010);
+        let zone = NvmeZoneDescriptor::parse(&data);
+        assert_eq!(
+            zone,
+            NvmeZoneDescriptor {
+                zone_type: NVME_ZONE_TYPE_SEQWRITE_REQ,
+                state: NvmeZoneState::ImplicitOpen,
+                za: 0x80,
+                zcap: 0x8000,
+                zslba: 0x10000,
+                wp: 0x10010,
+            }
+        );
+    }
+
+    #[test]
+    fn zone_descriptor_states() {
+        let states = [
+            (0x1, NvmeZoneState::Empty),
+            (0x3, NvmeZoneState::ExplicitOpen),
+            (0x4, NvmeZoneState::Closed),
+            (0xd, NvmeZoneState::ReadOnly),
+            (0xe, NvmeZoneState::Full),
+            (0xf, NvmeZoneState::Offline),
+            (0x5, NvmeZoneState::Unknown(0x5)),
+        ];
+        for (zs, state) in states {
+            let data = zone_desc(NVME_ZONE_TYPE_SEQWRITE_REQ, zs, 0, 0, 0, 0);
+            assert_eq!(NvmeZoneDescriptor::parse(&data).state, state);
+        }
+        // reserved bits next to the zone type and state are ignored
+        let mut data = zone_desc(NVME_ZONE_TYPE_SEQWRITE_REQ, 0xe, 0, 0, 0, 0);
+        data[0] |= 0xf0;
+        data[1] |= 0x0f;
+        let zone = NvmeZoneDescriptor::parse(&data);
+        assert_eq!(zone.zone_type, NVME_ZONE_TYPE_SEQWRITE_REQ);
+        assert_eq!(zone.state, NvmeZoneState::Full);
+    }
+
+    #[test]
+    fn css_cap_from_cap() {
+        let css = NvmeCssCap::from_cap(NVME_CAP_CSS_NVM | NVME_CAP_CSS_CSI | 0xff);
+        assert_eq!(css, NvmeCssCap { nvm: true, io_sets: true, no_io: false });
+        assert!(NvmeCssCap::from_cap(NVME_CAP_CSS_NO_IO).no_io);
+    }
+
+    #[test]
+    fn ns_ids_parse() {
+        let mut data = [0u8; 4096];
+        // eui64, then a descriptor of an unknown type, then the command set
+        data[0..4].copy_from_slice(&[NVME_NIDT_EUI64, 8, 0, 0]);
+        data[4..12].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        data[12..16].copy_from_slice(&[0x7f, 4, 0, 0]);
+        data[20..24].copy_from_slice(&[NVME_NIDT_CSI, 1, 0, 0]);
+        data[24] = NVME_CSI_ZNS;
+        let ids = NvmeNsIds::parse(&data);
+        assert_eq!(ids.eui64, Some([1, 2, 3, 4, 5, 6, 7, 8]));
+        assert_eq!((ids.nguid, ids.uuid), (None, None));
+        assert_eq!(ids.csi, NVME_CSI_ZNS);
+
+        // a descriptor running past the end is ignored
+        let ids = NvmeNsIds::parse(&[NVME_NIDT_UUID, 16, 0, 0, 1, 2]);
+        assert_eq!(ids, NvmeNsIds::default());
+    }
+
+    #[test]
+    fn id_iocs_find() {
+        let mut data = [0u8; 4096];
+        // profile 0: nvm, profile 1: nvm and zns
+        data[0] = 1 << NVME_CSI_NVM;
+        data[8] = 1 << NVME_CSI_NVM | 1 << NVME_CSI_ZNS;
+        let iocs = NvmeIdIocs::parse(&data);
+        assert_eq!(iocs.vectors.len(), 2);
+        assert_eq!(iocs.find(&[NVME_CSI_NVM]), Some(0));
+        assert_eq!(iocs.find(&[NVME_CSI_ZNS]), Some(1));
+        assert_eq!(iocs.find(&[NVME_CSI_KV]), None);
+    }
+
+    #[test]
+    fn kv_command_key() {
+        let cmd = NvmeKvCommand::new(NVME_CMD_KV_STORE, 1, b"0123456789");
+        assert_eq!(size_of::<NvmeKvCommand>(), 64);
+        assert_eq!(cmd.kl, 10);
+        assert_eq!(cmd.key_lo.to_le_bytes(), *b"01234567");
+        assert_eq!(cmd.key_hi.to_le_bytes(), [b'8', b'9', 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn kv_id_ns_formats() {
+        let mut data = [0u8; 4096];
+        data[0..8].copy_from_slice(&(1u64 << 30).to_le_bytes());
+        data[25] = 1;
+        // format 0: 16 byte keys, 1MiB values, format 1: 8 byte keys, 4MiB values, 100 keys
+        data[72..74].copy_from_slice(&16u16.to_le_bytes());
+        data[76..80].copy_from_slice(&(1u32 << 20).to_le_bytes());
+        data[88..90].copy_from_slice(&8u16.to_le_bytes());
+        data[92..96].copy_from_slice(&(4u32 << 20).to_le_bytes());
+        data[96..100].copy_from_slice(&100u32.to_le_bytes());
+
+        let id_ns = NvmeKvIdNs::parse(&data);
+        assert_eq!((id_ns.nsze, id_ns.nkvf), (1 << 30, 1));
+        assert_eq!(id_ns.kv_format(1).unwrap().mnk, 100);
+        assert!(id_ns.kv_format(2).is_none());
+        let common = id_ns.common_kv_format();
+        assert_eq!((common.kml, common.vml, common.mnk), (8, 1 << 20, 100));
+        assert_eq!(id_ns.format_in_use().vml, 1 << 20);
+
+        // a single format is the one in use
+        data[25] = 0;
+        let single = NvmeKvIdNs::parse(&data).format_in_use();
+        assert_eq!((single.kml, single.vml, single.mnk), (16, 1 << 20, 0));
+    }
+}
@@ -0,0 +1,139 @@
+// completion queue entry status field
+// bit 0: phase tag
+// bit 1-8: status code (SC)
+// bit 9-11: status code type (SCT)
+// bit 15: do not retry (DNR)
+pub const NVME_SCT_GENERIC: u8 = 0x0;
+pub const NVME_SCT_CMD_SPECIFIC: u8 = 0x1;
+pub const NVME_SCT_MEDIA: u8 = 0x2;
+pub const NVME_SCT_PATH: u8 = 0x3;
+pub const NVME_SCT_VENDOR: u8 = 0x7;
+
+// generic command status
+pub const NVME_SC_SUCCESS: u8 = 0x00;
+pub const NVME_SC_INVALID_OPCODE: u8 = 0x01;
+pub const NVME_SC_INVALID_FIELD: u8 = 0x02;
+pub const NVME_SC_ABORT_REQ: u8 = 0x07;
+pub const NVME_SC_LBA_RANGE: u8 = 0x80;
+pub const NVME_SC_RESERVATION_CONFLICT: u8 = 0x83;
+
+// path related status
+pub const NVME_SC_HOST_ABORTED: u8 = 0x71;
+
+// status the driver gives commands it abandoned after a controller fatal status
+pub const NVME_STATUS_HOST_ABORTED: u16 =
+    (NVME_SCT_PATH as u16) << 9 | (NVME_SC_HOST_ABORTED as u16) << 1;
+
+// command specific status of firmware commit
+pub const NVME_SC_FW_INVALID_SLOT: u8 = 0x06;
+pub const NVME_SC_FW_INVALID_IMAGE: u8 = 0x07;
+pub const NVME_SC_FW_NEEDS_CONV_RESET: u8 = 0x0b;
+pub const NVME_SC_FW_NEEDS_SUBSYS_RESET: u8 = 0x10;
+pub const NVME_SC_FW_NEEDS_RESET: u8 = 0x11;
+pub const NVME_SC_FW_NEEDS_MAX_TIME: u8 = 0x12;
+pub const NVME_SC_FW_ACTIVATION_PROHIBITED: u8 = 0x13;
+pub const NVME_SC_FW_OVERLAPPING_RANGE: u8 = 0x14;
+
+// command specific status of zoned namespace commands
+pub const NVME_SC_ZONE_BOUNDARY_ERROR: u8 = 0xb8;
+pub const NVME_SC_ZONE_FULL: u8 = 0xb9;
+pub const NVME_SC_ZONE_READ_ONLY: u8 = 0xba;
+pub const NVME_SC_ZONE_OFFLINE: u8 = 0xbb;
+pub const NVME_SC_ZONE_INVALID_WRITE: u8 = 0xbc;
+pub const NVME_SC_ZONE_TOO_MANY_ACTIVE: u8 = 0xbd;
+pub const NVME_SC_ZONE_TOO_MANY_OPEN: u8 = 0xbe;
+pub const NVME_SC_ZONE_INVALID_TRANSITION: u8 = 0xbf;
+
+// command specific status of key value commands
+pub const NVME_SC_KV_INVALID_VALUE_SIZE: u8 = 0x85;
+pub const NVME_SC_KV_INVALID_KEY_SIZE: u8 = 0x86;
+pub const NVME_SC_KV_KEY_NOT_EXIST: u8 = 0x87;
+pub const NVME_SC_KV_UNRECOVERED: u8 = 0x88;
+pub const NVME_SC_KV_KEY_EXISTS: u8 = 0x89;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeError {
+    // command completed with a non-zero status
+    CommandFailed { sct: u8, sc: u8, dnr: bool },
+    // namespace is reserved by another host
+    ReservationConflict,
+    // the controller or namespace does not support the operation
+    NotSupported,
+    // request is outside the limits reported by the controller
+    InvalidArgument,
+    // no completion within the allowed time
+    Timeout,
+    // sanitize status log reports the last sanitize operation failed
+    SanitizeFailed,
+    // CSTS.CFS is set or the device is gone, the command was aborted by the host
+    ControllerFatal,
+    // PMRSTS.ERR reported while enabling or disabling the persistent memory region
+    PmrFailed { err: u8 },
+    // BPINFO.BRS reports an error for the boot partition read
+    BootPartitionReadFailed,
+    // host verification of the protection information of an lba failed
+    PiCheckFailed { lba: u64 },
+    // key value namespace has no such key
+    KvKeyNotFound,
+    // store without overwrite of a key already present
+    KvKeyExists,
+}
+
+pub type NvmeResult<T> = Result<T, NvmeError>;
+
+impl NvmeError {
+    // decode the status field of a completion queue entry
+    pub fn from_status(status: u16) -> NvmeResult<()> {
+        let sc = ((status >> 1) & 0xff) as u8;
+        let sct = ((status >> 9) & 0x7) as u8;
+        let dnr = status & (1 << 15) != 0;
+
+        match (sct, sc) {
+            (NVME_SCT_GENERIC, NVME_SC_SUCCESS) => Ok(()),
+            (NVME_SCT_GENERIC, NVME_SC_RESERVATION_CONFLICT) => Err(NvmeError::ReservationConflict),
+            (NVME_SCT_PATH, NVME_SC_HOST_ABORTED) => Err(NvmeError::ControllerFatal),
+            _ => Err(NvmeError::CommandFailed { sct, sc, dnr }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // status field as the controller posts it, phase tag set
+    fn status(sct: u8, sc: u8, dnr: bool) -> u16 {
+        (dnr as u16) << 15 | (sct as u16) << 9 | (sc as u16) << 1 | 1
+    }
+
+    #[test]
+    fn from_status_success_ignores_phase() {
+        assert_eq!(NvmeError::from_status(0), Ok(()));
+        let success = status(NVME_SCT_GENERIC, NVME_SC_SUCCESS, false);
+        assert_eq!(NvmeError::from_status(success), Ok(()));
+    }
+
+    #[test]
+    fn from_status_decodes_failure() {
+        assert_eq!(
+            NvmeError::from_status(status(NVME_SCT_GENERIC, NVME_SC_INVALID_FIELD, true)),
+            Err(NvmeError::CommandFailed { sct: 0, sc: NVME_SC_INVALID_FIELD, dnr: true })
+        );
+        assert_eq!(
+            NvmeError::from_status(status(NVME_SCT_MEDIA, 0x81, false)),
+            Err(NvmeError::CommandFailed { sct: NVME_SCT_MEDIA, sc: 0x81, dnr: false })
+        );
+    }
+
+    #[test]
+    fn from_status_reservation_conflict() {
+        let conflict = status(NVME_SCT_GENERIC, NVME_SC_RESERVATION_CONFLICT, false);
+        assert_eq!(NvmeError::from_status(conflict), Err(NvmeError::ReservationConflict));
+    }
+
+    #[test]
+    fn from_status_host_aborted() {
+        let status = NvmeError::from_status(NVME_STATUS_HOST_ABORTED);
+        assert_eq!(status, Err(NvmeError::ControllerFatal));
+    }
+}
@@ -0,0 +1,258 @@
+use alloc::slice;
+use alloc::vec::Vec;
+
+use super::nvme::NvmeInterface;
+use super::nvme_defs::*;
+use super::nvme_error::*;
+use super::nvme_queue::PAGE_SIZE;
+use crate::dma::DmaAllocator;
+use crate::irq::IrqController;
+use crate::timer::Timer;
+
+// bounce buffer of a key list
+const NVME_KV_LIST_BUF_SIZE: usize = PAGE_SIZE * 2;
+
+// namespace of the key value command set, keys are 1 to 16 bytes
+pub struct KvNamespace<'a, D: DmaAllocator, I: IrqController, T: Timer> {
+    nvme: &'a NvmeInterface<D, I, T>,
+    nsid: u32,
+    info: NvmeKvIdNs,
+    // kv format the namespace is formatted with
+    format: NvmeKvFormat,
+    // largest value of one command, limited by the kv format and the transfer size
+    max_value: usize,
+}
+
+impl<'a, D: DmaAllocator, I: IrqController, T: Timer> KvNamespace<'a, D, I, T> {
+    pub(crate) fn new(
+        nvme: &'a NvmeInterface<D, I, T>,
+        nsid: u32,
+        info: NvmeKvIdNs,
+        format: NvmeKvFormat,
+        max_transfer: usize,
+    ) -> Self {
+        let max_value = (format.vml as usize).min(max_transfer);
+        Self { nvme, nsid, info, format, max_value }
+    }
+
+    pub fn nsid(&self) -> u32 {
+        self.nsid
+    }
+
+    pub fn info(&self) -> &NvmeKvIdNs {
+        &self.info
+    }
+
+    pub fn format(&self) -> NvmeKvFormat {
+        self.format
+    }
+
+    pub fn max_key_len(&self) -> usize {
+        (self.format.kml as usize).min(NVME_KV_MAX_KEY_LEN)
+    }
+
+    pub fn max_value_len(&self) -> usize {
+        self.max_value
+    }
+
+    // option is NVME_KV_STORE_* bits, the value need not be physically contiguous. a
+    // value that is not dword aligned is copied through a dma bounce buffer
+    pub fn store(&self, key: &[u8], value: &[u8], option: u8) -> NvmeResult<()> {
+        self.check_key(key)?;
+        if value.len() > self.max_value {
+            return Err(NvmeError::InvalidArgument);
+        }
+
+        let mut cmd = NvmeKvCommand::new(NVME_CMD_KV_STORE, self.nsid, key);
+        cmd.size = value.len() as u32;
+        cmd.option = option;
+        let va = value.as_ptr() as usize;
+        if value.is_empty() || va % 4 == 0 {
+            return self.submit(cmd, va, value.len()).map(|_| ());
+        }
+
+        let bounce_va = D::dma_alloc(value.len());
+        let bounce = unsafe { slice::from_raw_parts_mut(bounce_va as *mut u8, value.len()) };
+        bounce.copy_from_slice(value);
+        let result = self.submit(cmd, bounce_va, value.len());
+        D::dma_dealloc(bounce_va, value.len());
+        result.map(|_| ())
+    }
+
+    // read the value of key into buf, return the full size of the value which may be
+    // larger than buf. an empty buf only queries the size, a buf that is not dword
+    // aligned is filled through a dma bounce buffer
+    pub fn retrieve(&self, key: &[u8], buf: &mut [u8]) -> NvmeResult<usize> {
+        self.check_key(key)?;
+        let len = buf.len().min(self.max_value);
+
+        let mut cmd = NvmeKvCommand::new(NVME_CMD_KV_RETRIEVE, self.nsid, key);
+        cmd.size = len as u32;
+        let va = buf.as_mut_ptr() as usize;
+        if len == 0 || va % 4 == 0 {
+            let size = self.submit(cmd, va, len)? as u32 as usize;
+            return Ok(size);
+        }
+
+        let bounce_va = D::dma_alloc(len);
+        let result = self.submit(cmd, bounce_va, len).map(|result| {
+            let size = result as u32 as usize;
+            let copied = size.min(len);
+            let bounce = unsafe { slice::from_raw_parts(bounce_va as *const u8, copied) };
+            buf[..copied].copy_from_slice(bounce);
+            size
+        });
+        D::dma_dealloc(bounce_va, len);
+        result
+    }
+
+    pub fn delete(&self, key: &[u8]) -> NvmeResult<()> {
+        self.check_key(key)?;
+        let cmd = NvmeKvCommand::new(NVME_CMD_KV_DELETE, self.nsid, key);
+        self.submit(cmd, 0, 0).map(|_| ())
+    }
+
+    pub fn exist(&self, key: &[u8]) -> NvmeResult<bool> {
+        self.check_key(key)?;
+        let cmd = NvmeKvCommand::new(NVME_CMD_KV_EXIST, self.nsid, key);
+        match self.submit(cmd, 0, 0) {
+            Ok(_) => Ok(true),
+            Err(NvmeError::KvKeyNotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // keys from key onwards in the controller's order, at most as many as fit in one
+    // list buffer. an empty key starts from the first key
+    pub fn list(&self, key: &[u8]) -> NvmeResult<Vec<Vec<u8>>> {
+        if key.len() > self.max_key_len() {
+            return Err(NvmeError::InvalidArgument);
+        }
+
+        let buf_va = D::dma_alloc(NVME_KV_LIST_BUF_SIZE);
+        let mut cmd = NvmeKvCommand::new(NVME_CMD_KV_LIST, self.nsid, key);
+        cmd.size = NVME_KV_LIST_BUF_SIZE as u32;
+        let result = self.submit(cmd, buf_va, NVME_KV_LIST_BUF_SIZE).map(|_| {
+            let data = unsafe { slice::from_raw_parts(buf_va as *const u8, NVME_KV_LIST_BUF_SIZE) };
+            nvme_kv_parse_list(data)
+        });
+        D::dma_dealloc(buf_va, NVME_KV_LIST_BUF_SIZE);
+        result
+    }
+
+    fn check_key(&self, key: &[u8]) -> NvmeResult<()> {
+        if key.is_empty() || key.len() > self.max_key_len() {
+            return Err(NvmeError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    // return dword0 of the completion
+    fn submit(&self, cmd: NvmeKvCommand, buf_va: usize, len: usize) -> NvmeResult<u64> {
+        let common_cmd = unsafe { core::mem::transmute(cmd) };
+        let cqe = if len == 0 {
+            self.nvme.submit_io_command(common_cmd)?
+        } else {
+            self.nvme.submit_io_command_buf(common_cmd, buf_va, len, NvmeQueuePriority::Medium)?
+        };
+        nvme_kv_status(cqe.status).map(|_| cqe.result)
+    }
+}
+
+// command specific status codes 0x85..0x89 only mean kv errors for kv commands
+fn nvme_kv_status(status: u16) -> NvmeResult<()> {
+    match NvmeError::from_status(status) {
+        Err(NvmeError::CommandFailed { sct: NVME_SCT_CMD_SPECIFIC, sc, .. }) => match sc {
+            NVME_SC_KV_KEY_NOT_EXIST => Err(NvmeError::KvKeyNotFound),
+            NVME_SC_KV_KEY_EXISTS => Err(NvmeError::KvKeyExists),
+            _ => NvmeError::from_status(status),
+        },
+        result => result,
+    }
+}
+
+// number of keys, then per key a 2 byte length and the key padded to 4 bytes
+fn nvme_kv_parse_list(data: &[u8]) -> Vec<Vec<u8>> {
+    let count = le32(data, 0) as usize;
+    let mut keys = Vec::new();
+    let mut offset = 4;
+    for _ in 0..count {
+        if offset + 2 > data.len() {
+            break;
+        }
+        let kl = le16(data, offset) as usize;
+        let end = offset + 2 + kl;
+        if end > data.len() {
+            break;
+        }
+        keys.push(data[offset + 2..end].to_vec());
+        offset = (end + 3) & !3;
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // key list with keys padded to 4 bytes
+    fn key_list(count: u32, keys: &[&[u8]]) -> Vec<u8> {
+        let mut data = count.to_le_bytes().to_vec();
+        for key in keys {
+            data.extend_from_slice(&(key.len() as u16).to_le_bytes());
+            data.extend_from_slice(key);
+            while data.len() % 4 != 0 {
+                data.push(0);
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn kv_status_decoding() {
+        let status = |sc: u8| (NVME_SCT_CMD_SPECIFIC as u16) << 9 | (sc as u16) << 1;
+        assert_eq!(nvme_kv_status(0), Ok(()));
+        assert_eq!(nvme_kv_status(status(NVME_SC_KV_KEY_NOT_EXIST)), Err(NvmeError::KvKeyNotFound));
+        assert_eq!(nvme_kv_status(status(NVME_SC_KV_KEY_EXISTS)), Err(NvmeError::KvKeyExists));
+        assert_eq!(
+            nvme_kv_status(status(NVME_SC_KV_INVALID_KEY_SIZE)),
+            Err(NvmeError::CommandFailed {
+                sct: NVME_SCT_CMD_SPECIFIC,
+                sc: NVME_SC_KV_INVALID_KEY_SIZE,
+                dnr: false
+            })
+        );
+        // the same codes of other command sets stay generic command failures
+        assert_eq!(
+            NvmeError::from_status(status(NVME_SC_KV_KEY_NOT_EXIST)),
+            Err(NvmeError::CommandFailed {
+                sct: NVME_SCT_CMD_SPECIFIC,
+                sc: NVME_SC_KV_KEY_NOT_EXIST,
+                dnr: false
+            })
+        );
+    }
+
+    #[test]
+    fn parse_list_keys() {
+        let data = key_list(3, &[b"a", b"key1", b"0123456789abcdef"]);
+        let keys = nvme_kv_parse_list(&data);
+        assert_eq!(keys, [b"a".to_vec(), b"key1".to_vec(), b"0123456789abcdef".to_vec()]);
+    }
+
+    #[test]
+    fn parse_list_empty() {
+        assert!(nvme_kv_parse_list(&key_list(0, &[])).is_empty());
+    }
+
+    #[test]
+    fn parse_list_stops_at_buffer_end() {
+        // the count claims more keys than the buffer holds
+        let mut data = key_list(3, &[b"key1", b"key2"]);
+        assert_eq!(nvme_kv_parse_list(&data), [b"key1".to_vec(), b"key2".to_vec()]);
+
+        // the last key is cut short
+        data.truncate(data.len() - 4);
+        assert_eq!(nvme_kv_parse_list(&data), [b"key1".to_vec()]);
+    }
+}
@@ -0,0 +1,94 @@
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+use super::nvme_defs::*;
+use super::nvme_error::*;
+
+// byte addressable view of an enabled persistent memory region
+// writes are persistent only after a call to persist
+pub struct PmrRegion {
+    va: usize,
+    len: usize,
+    // nvme register bar, PMRSTS is read as a write barrier
+    bar: usize,
+    // NVME_PMRWBM_* bits
+    wbm: u8,
+}
+
+impl PmrRegion {
+    pub(crate) fn new(va: usize, len: usize, bar: usize, wbm: u8) -> Self {
+        Self { va, len, bar, wbm }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> NvmeResult<()> {
+        self.check_range(offset, buf.len())?;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = unsafe { read_volatile((self.va + offset + i) as *const u8) };
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, offset: usize, data: &[u8]) -> NvmeResult<()> {
+        self.check_range(offset, data.len())?;
+        for (i, byte) in data.iter().enumerate() {
+            unsafe { write_volatile((self.va + offset + i) as *mut u8, *byte) }
+        }
+        Ok(())
+    }
+
+    // every write issued before the barrier is persistent when it returns, NotSupported
+    // when PMRCAP.PMRWBM reports no barrier mechanism
+    pub fn persist(&self) -> NvmeResult<()> {
+        fence(Ordering::SeqCst);
+        if self.wbm & NVME_PMRWBM_STS_READ != 0 {
+            unsafe { read_volatile((self.bar + NVME_REG_PMRSTS) as *const u32) };
+        } else if self.wbm & NVME_PMRWBM_PMR_READ != 0 && self.len > 0 {
+            unsafe { read_volatile(self.va as *const u8) };
+        } else {
+            return Err(NvmeError::NotSupported);
+        }
+        fence(Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn check_range(&self, offset: usize, len: usize) -> NvmeResult<()> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(NvmeError::InvalidArgument),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_write_in_range() {
+        let mut mem = [0u8; 64];
+        let mut pmr = PmrRegion::new(mem.as_mut_ptr() as usize, mem.len(), 0, 0);
+        pmr.write(60, &[1, 2, 3, 4]).unwrap();
+        let mut buf = [0u8; 2];
+        pmr.read(61, &mut buf).unwrap();
+        assert_eq!(buf, [2, 3]);
+        assert_eq!(pmr.write(61, &[0; 4]), Err(NvmeError::InvalidArgument));
+        assert_eq!(pmr.read(usize::MAX, &mut buf), Err(NvmeError::InvalidArgument));
+        assert_eq!(mem[60..], [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn persist_needs_barrier() {
+        let mut mem = [0u8; 64];
+        let va = mem.as_mut_ptr() as usize;
+        assert_eq!(PmrRegion::new(va, 64, 0, 0).persist(), Err(NvmeError::NotSupported));
+        assert_eq!(PmrRegion::new(va, 64, 0, NVME_PMRWBM_PMR_READ).persist(), Ok(()));
+    }
+}
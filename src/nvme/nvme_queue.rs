@@ -1,14 +1,100 @@
+use alloc::collections::BTreeMap;
+use alloc::collections::VecDeque;
 use alloc::slice;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::marker::PhantomData;
 use volatile::Volatile;
 
 use super::NvmeCommonCommand;
 use super::NvmeCompletion;
+use super::NvmeError;
 use super::NVME_QUEUE_DEPTH;
 
 use crate::dma::DmaAllocator;
 
-const PAGE_SIZE: usize = 4096;
+pub(crate) const PAGE_SIZE: usize = 4096;
+
+// size of the dma buffer used for command data (identify, range list...)
+pub const NVME_DATA_BUF_SIZE: usize = PAGE_SIZE * 4;
+
+// dma buffer for the data of a command, its owner keeps it locked from filling the
+// buffer until the completion has been parsed
+#[derive(Debug)]
+pub struct NvmeDataBuf<D: DmaAllocator> {
+    dma_data: PhantomData<D>,
+    va: usize,
+    pub pa: usize,
+    // va was allocated by dma_alloc
+    host: bool,
+}
+
+impl<D: DmaAllocator> NvmeDataBuf<D> {
+    pub fn new() -> Self {
+        let va = D::dma_alloc(NVME_DATA_BUF_SIZE);
+        Self {
+            dma_data: PhantomData,
+            va,
+            pa: D::virt_to_phys(va),
+            host: true,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.va as *const u8, NVME_DATA_BUF_SIZE) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.va as *mut u8, NVME_DATA_BUF_SIZE) }
+    }
+
+    // move the buffer outside host dma memory (controller memory buffer),
+    // NVME_DATA_BUF_SIZE bytes. the host dma buffer is freed
+    pub fn relocate(&mut self, va: usize, pa: usize) {
+        if self.host {
+            D::dma_dealloc(self.va, NVME_DATA_BUF_SIZE);
+            self.host = false;
+        }
+        self.va = va;
+        self.pa = pa;
+    }
+}
+
+impl<D: DmaAllocator> Default for NvmeDataBuf<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// number of failed commands remembered per queue
+pub const NVME_FAILED_HISTORY: usize = 16;
+
+// command id 0xffff means "not associated with a command" in the error log
+const NVME_CID_INVALID: u16 = 0xffff;
+
+// one page of prp entries per queue
+pub const NVME_PRP_LIST_ENTRIES: usize = PAGE_SIZE / 8;
+
+// a command that completed with an error status
+#[derive(Debug, Clone, Copy)]
+pub struct NvmeFailedCommand {
+    pub sqid: u16,
+    pub cmd: NvmeCommonCommand,
+    pub status: u16,
+}
+
+// a submitted command waiting for its completion
+#[derive(Debug, Clone, Copy)]
+pub struct NvmeInflight {
+    pub cmd: NvmeCommonCommand,
+    // Timer::current_time_us after which the command is considered stuck
+    pub deadline_us: u64,
+    // an abort has been issued for the command
+    pub aborted: bool,
+    // a thread waits for the completion, keep it in NvmeQueue::completed when someone
+    // else reaps it
+    pub waited: bool,
+}
 
 #[derive(Debug)]
 pub struct NvmeQueue<D: DmaAllocator> {
@@ -32,16 +118,37 @@ pub struct NvmeQueue<D: DmaAllocator> {
 
     pub sq_pa: usize,
     pub cq_pa: usize,
-    pub data_pa: usize,
+    // prp list pages (va, pa) not used by a command in flight, the pages may live outside
+    // host dma memory (controller memory buffer)
+    prp_free: Vec<(usize, usize)>,
+    // prp list page of each command in flight that needs one, by command id
+    prp_used: BTreeMap<u16, (usize, usize)>,
+    // page filled for the command being prepared, it is the command's once it is queued
+    prp_next: Option<(usize, usize)>,
+
+    // shadow sq tail doorbell (cq head at +4) and its eventidx entry, 0 when the
+    // controller has no doorbell buffer
+    pub dbbuf_db: usize,
+    pub dbbuf_ei: usize,
+
+    pub next_cid: u16,
+    // submitted commands waiting for completion, by command id
+    pub inflight: BTreeMap<u16, NvmeInflight>,
+    // completions of waited commands reaped by someone other than their waiter
+    pub completed: BTreeMap<u16, NvmeCompletion>,
+    // most recent failed commands, oldest first
+    pub failed: VecDeque<NvmeFailedCommand>,
+    // completed asynchronous event requests not processed yet (admin queue only)
+    pub async_events: VecDeque<NvmeCompletion>,
 }
 
 impl<D: DmaAllocator> NvmeQueue<D> {
-    pub fn new(qid: usize, db_offset: usize) -> Self {
-        let data_va = D::dma_alloc(PAGE_SIZE * 4);
+    pub fn new(qid: usize, db_offset: usize, q_depth: usize) -> Self {
+        let prp_va = D::dma_alloc(PAGE_SIZE);
         let sq_va = D::dma_alloc(NVME_QUEUE_DEPTH*64);
         let cq_va = D::dma_alloc(NVME_QUEUE_DEPTH*16);
 
-        let data_pa = D::virt_to_phys(data_va);
+        let prp_pa = D::virt_to_phys(prp_va);
         let sq_pa = D::virt_to_phys(sq_va);
         let cq_pa = D::virt_to_phys(cq_va);
 
@@ -59,7 +166,7 @@ impl<D: DmaAllocator> NvmeQueue<D> {
             sq: submit_queue,
             cq: complete_queue,
             db_offset,
-            q_depth: NVME_QUEUE_DEPTH,
+            q_depth,
             qid,
             cq_head: 0,
             cq_phase: 1,
@@ -67,7 +174,16 @@ impl<D: DmaAllocator> NvmeQueue<D> {
             last_sq_tail: 0,
             sq_pa,
             cq_pa,
-            data_pa,
+            prp_free: vec![(prp_va, prp_pa)],
+            prp_used: BTreeMap::new(),
+            prp_next: None,
+            dbbuf_db: 0,
+            dbbuf_ei: 0,
+            next_cid: 0,
+            inflight: BTreeMap::new(),
+            completed: BTreeMap::new(),
+            failed: VecDeque::new(),
+            async_events: VecDeque::new(),
         }
     }
 
@@ -77,4 +193,281 @@ impl<D: DmaAllocator> NvmeQueue<D> {
         self.sq_tail = 0;
         self.last_sq_tail = 0;
     }
+
+    // next command id not used by an in flight command
+    pub fn alloc_cid(&mut self) -> u16 {
+        loop {
+            let cid = self.next_cid;
+            self.next_cid = if cid + 1 == NVME_CID_INVALID { 0 } else { cid + 1 };
+            if !self.inflight.contains_key(&cid) && !self.completed.contains_key(&cid) {
+                return cid;
+            }
+        }
+    }
+
+    // empty the completion queue and rewind the queue, used after a controller reset
+    pub fn reset(&mut self) {
+        for cqe in self.cq.iter_mut().take(self.q_depth) {
+            cqe.write(NvmeCompletion::default());
+        }
+        self.nvme_init_queue();
+    }
+
+    // retire the command of a completion entry, remember it if it failed and keep the
+    // completion for its waiter
+    pub fn complete_command(&mut self, cqe: &NvmeCompletion) -> Option<NvmeCommonCommand> {
+        let inflight = self.inflight.remove(&cqe.command_id)?;
+        self.release_prp_list(cqe.command_id);
+        if NvmeError::from_status(cqe.status).is_err() {
+            self.fail_command(inflight.cmd, cqe.status);
+        }
+        if inflight.waited {
+            self.completed.insert(cqe.command_id, *cqe);
+        }
+        Some(inflight.cmd)
+    }
+
+    // remember a failed command, the oldest one is forgotten once the history is full
+    pub fn fail_command(&mut self, cmd: NvmeCommonCommand, status: u16) {
+        self.release_prp_list(cmd.command_id);
+        if self.failed.len() == NVME_FAILED_HISTORY {
+            self.failed.pop_front();
+        }
+        self.failed.push_back(NvmeFailedCommand {
+            sqid: self.qid as u16,
+            cmd,
+            status,
+        });
+    }
+
+    // prp list page for the command being prepared and its bus address. a page is
+    // allocated when every page belongs to a command in flight
+    pub fn prp_list(&mut self) -> (&mut [u64], usize) {
+        let (prp_va, prp_pa) = match self.prp_next {
+            Some(page) => page,
+            None => {
+                let page = self.prp_free.pop().unwrap_or_else(|| {
+                    let prp_va = D::dma_alloc(PAGE_SIZE);
+                    (prp_va, D::virt_to_phys(prp_va))
+                });
+                self.prp_next = Some(page);
+                page
+            }
+        };
+        let prp_list =
+            unsafe { slice::from_raw_parts_mut(prp_va as *mut u64, NVME_PRP_LIST_ENTRIES) };
+        (prp_list, prp_pa)
+    }
+
+    // the prp list page prepared last belongs to command cid until it leaves the queue
+    pub fn claim_prp_list(&mut self, cid: u16) {
+        if let Some(page) = self.prp_next.take() {
+            self.prp_used.insert(cid, page);
+        }
+    }
+
+    pub fn release_prp_list(&mut self, cid: u16) {
+        if let Some(page) = self.prp_used.remove(&cid) {
+            self.prp_free.push(page);
+        }
+    }
+
+    // move the submission queue, the sq must be created again on the controller
+    pub fn set_sq(&mut self, sq_va: usize, sq_pa: usize) {
+        self.sq = unsafe {
+            slice::from_raw_parts_mut(sq_va as *mut Volatile<NvmeCommonCommand>, self.q_depth)
+        };
+        self.sq_pa = sq_pa;
+        self.sq_tail = 0;
+        self.last_sq_tail = 0;
+    }
+
+    // add a page to the prp list pages, it is used before the pages already there
+    pub fn set_prp_list(&mut self, prp_va: usize, prp_pa: usize) {
+        self.prp_free.push((prp_va, prp_pa));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+
+    // page aligned heap memory, physical and virtual addresses are the same
+    struct HeapDma;
+
+    impl DmaAllocator for HeapDma {
+        fn dma_alloc(size: usize) -> usize {
+            let layout = Layout::from_size_align(size, PAGE_SIZE).unwrap();
+            unsafe { alloc_zeroed(layout) as usize }
+        }
+
+        fn dma_dealloc(addr: usize, size: usize) -> usize {
+            let layout = Layout::from_size_align(size, PAGE_SIZE).unwrap();
+            unsafe { dealloc(addr as *mut u8, layout) };
+            0
+        }
+
+        fn phys_to_virt(phys: usize) -> usize {
+            phys
+        }
+
+        fn virt_to_phys(virt: usize) -> usize {
+            virt
+        }
+    }
+
+    // what submitting does to the queue bookkeeping, without a doorbell
+    fn submit(queue: &mut NvmeQueue<HeapDma>, opcode: u8) -> u16 {
+        let mut cmd = NvmeCommonCommand::new();
+        cmd.opcode = opcode;
+        cmd.command_id = queue.alloc_cid();
+        let inflight = NvmeInflight {
+            cmd,
+            deadline_us: u64::MAX,
+            aborted: false,
+            waited: false,
+        };
+        queue.inflight.insert(cmd.command_id, inflight);
+        cmd.command_id
+    }
+
+    fn completion(command_id: u16, status: u16) -> NvmeCompletion {
+        NvmeCompletion {
+            sq_id: 1,
+            command_id,
+            status,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn alloc_cid_skips_inflight() {
+        let mut queue = NvmeQueue::<HeapDma>::new(1, 0, 64);
+        assert_eq!(submit(&mut queue, 0x02), 0);
+        assert_eq!(submit(&mut queue, 0x02), 1);
+        queue.next_cid = 0;
+        assert_eq!(queue.alloc_cid(), 2);
+    }
+
+    #[test]
+    fn alloc_cid_wraps_before_invalid() {
+        let mut queue = NvmeQueue::<HeapDma>::new(1, 0, 64);
+        queue.next_cid = NVME_CID_INVALID - 1;
+        assert_eq!(queue.alloc_cid(), NVME_CID_INVALID - 1);
+        assert_eq!(queue.alloc_cid(), 0);
+    }
+
+    #[test]
+    fn complete_command_retires_success() {
+        let mut queue = NvmeQueue::<HeapDma>::new(1, 0, 64);
+        let cid = submit(&mut queue, 0x02);
+        queue.complete_command(&completion(cid, 1));
+        assert!(queue.inflight.is_empty());
+        assert!(queue.failed.is_empty());
+    }
+
+    #[test]
+    fn complete_command_keeps_failure_history() {
+        let mut queue = NvmeQueue::<HeapDma>::new(1, 0, 64);
+        for opcode in 0..NVME_FAILED_HISTORY as u8 + 2 {
+            let cid = submit(&mut queue, opcode);
+            // invalid field in command
+            queue.complete_command(&completion(cid, 0x2 << 1));
+        }
+        assert_eq!(queue.failed.len(), NVME_FAILED_HISTORY);
+        let oldest = queue.failed.front().unwrap();
+        assert_eq!(oldest.cmd.opcode, 2);
+        assert_eq!(oldest.sqid, 1);
+        assert_eq!(oldest.status, 0x2 << 1);
+        assert_eq!(queue.failed.back().unwrap().cmd.opcode, NVME_FAILED_HISTORY as u8 + 1);
+    }
+
+    #[test]
+    fn complete_command_returns_command() {
+        let mut queue = NvmeQueue::<HeapDma>::new(1, 0, 64);
+        let cid = submit(&mut queue, 0x0c);
+        let cmd = queue.complete_command(&completion(cid, 1)).unwrap();
+        assert_eq!((cmd.opcode, cmd.command_id), (0x0c, cid));
+        assert!(queue.complete_command(&completion(cid, 1)).is_none());
+    }
+
+    #[test]
+    fn reset_rewinds_and_keeps_inflight() {
+        let mut queue = NvmeQueue::<HeapDma>::new(1, 0, 64);
+        let cid = submit(&mut queue, 0x02);
+        queue.cq[0].write(completion(cid, 1));
+        queue.cq_head = 1;
+        queue.sq_tail = 5;
+        queue.last_sq_tail = 5;
+        queue.reset();
+        assert_eq!((queue.cq_head, queue.cq_phase, queue.sq_tail), (0, 1, 0));
+        assert_eq!(queue.cq[0].read().status, 0);
+        assert!(queue.inflight.contains_key(&cid));
+    }
+
+    #[test]
+    fn complete_command_keeps_waited_completion() {
+        let mut queue = NvmeQueue::<HeapDma>::new(1, 0, 64);
+        let waited = submit(&mut queue, 0x02);
+        queue.inflight.get_mut(&waited).unwrap().waited = true;
+        let other = submit(&mut queue, 0x02);
+        queue.complete_command(&completion(waited, 1));
+        queue.complete_command(&completion(other, 1));
+        assert_eq!(queue.completed.len(), 1);
+        assert_eq!(queue.completed[&waited].command_id, waited);
+
+        // the id stays reserved until the waiter took the completion
+        queue.next_cid = waited;
+        assert_ne!(queue.alloc_cid(), waited);
+    }
+
+    #[test]
+    fn prp_list_follows_relocation() {
+        let mut queue = NvmeQueue::<HeapDma>::new(1, 0, 64);
+        let page = HeapDma::dma_alloc(PAGE_SIZE);
+        // a bus address unrelated to the cpu mapping, as in a pci bar
+        queue.set_prp_list(page, 0xfe00_0000);
+        let (prp_list, prp_pa) = queue.prp_list();
+        prp_list[1] = 0x1234;
+        assert_eq!(prp_pa, 0xfe00_0000);
+        assert_eq!(unsafe { *((page + 8) as *const u64) }, 0x1234);
+    }
+
+    #[test]
+    fn prp_list_per_command() {
+        let mut queue = NvmeQueue::<HeapDma>::new(1, 0, 64);
+        let first = queue.prp_list().1;
+        // preparing again before the command is queued reuses the page
+        assert_eq!(queue.prp_list().1, first);
+        let cid = submit(&mut queue, 0x02);
+        queue.claim_prp_list(cid);
+
+        // the page stays with the command in flight
+        let second = queue.prp_list().1;
+        assert_ne!(second, first);
+        let other = submit(&mut queue, 0x02);
+        queue.claim_prp_list(other);
+
+        queue.complete_command(&completion(cid, 1));
+        assert_eq!(queue.prp_list().1, first);
+        let failed = queue.inflight.remove(&other).unwrap().cmd;
+        queue.fail_command(failed, 0x2 << 1);
+        assert_eq!(queue.prp_free.last().map(|page| page.1), Some(second));
+    }
+
+    #[test]
+    fn data_buf_relocate() {
+        let mut buf = NvmeDataBuf::<HeapDma>::new();
+        let va = HeapDma::dma_alloc(NVME_DATA_BUF_SIZE);
+        buf.relocate(va, 0xfe01_0000);
+        buf.as_mut_slice()[0] = 0x5a;
+        assert_eq!(buf.pa, 0xfe01_0000);
+        assert_eq!(buf.as_slice().as_ptr() as usize, va);
+        assert_eq!(unsafe { *(va as *const u8) }, 0x5a);
+        // moving again leaves the previous location alone, it is not host dma memory
+        buf.relocate(va + PAGE_SIZE, 0xfe02_0000);
+        assert_eq!(buf.pa, 0xfe02_0000);
+        HeapDma::dma_dealloc(va, NVME_DATA_BUF_SIZE);
+    }
 }
@@ -0,0 +1,9 @@
+pub trait Timer {
+    // monotonic time in microseconds
+    fn current_time_us() -> u64;
+
+    fn delay_us(us: u64) {
+        let start = Self::current_time_us();
+        while Self::current_time_us() - start < us {}
+    }
+}